@@ -0,0 +1,438 @@
+use crate::types::*;
+use candid::Nat;
+use ic_cdk::api::call::CallResult;
+use ic_cdk::export::Principal;
+use itertools::Itertools;
+
+pub const DEFAULT_CACHE_TTL: u32 = 5 * 60;
+pub const MAX_CACHE_TTL: u32 = 24 * 60 * 60;
+pub const MAX_CACHE_ENTRIES: usize = 10_000;
+
+// One source of access to a dataset, as reported by a provider
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessGrant {
+    pub tier: Option<String>,
+    pub token: Option<u32>,
+    pub columns: Vec<u8>,
+    pub is_gdpr: bool,
+    pub is_enabled: bool,
+    pub time_limit_seconds: u32,
+    pub expire_at: Option<u64>,
+}
+
+impl AccessGrant {
+    pub fn new(tier: Option<String>, columns: Vec<u8>, is_gdpr: bool) -> Self {
+        AccessGrant { tier, token: None, columns, is_gdpr, is_enabled: true, time_limit_seconds: 0, expire_at: None }
+    }
+
+    // Unnamed NFTs are told apart by the columns they unlock, copies of one tier by their token index
+    fn key(&self) -> String {
+        let nft = match &self.tier {
+            Some(tier) => tier.clone(),
+            None => self.columns.iter().join(","),
+        };
+        match self.token {
+            Some(index) => format!("{}#{}", nft, index),
+            None => nft,
+        }
+    }
+}
+
+impl From<&NftMetadata> for AccessGrant {
+    fn from(nft: &NftMetadata) -> Self {
+        AccessGrant {
+            token: nft.tokenIndex,
+            is_enabled: nft.isEnabled,
+            time_limit_seconds: nft.timeLimitSeconds,
+            ..AccessGrant::new(nft.name.clone(), nft.dimensionRestrictList.clone(), nft.isGdrpEnabled)
+        }
+    }
+}
+
+// Errors mean the provider could not answer, not that the user has no access
+pub trait AccessProvider {
+    async fn grants(&self, dataset_id: u32, user: Principal) -> Result<Vec<AccessGrant>, String>;
+}
+
+impl AccessProvider for ExtNftProvider {
+    async fn grants(&self, dataset_id: u32, user: Principal) -> Result<Vec<AccessGrant>, String> {
+        let result: CallResult<(Vec<NftMetadata>,)> = ic_cdk::call(self.canister_id, "getUserDatasetAccess", (dataset_id, user)).await;
+        match result {
+            Ok((nfts,)) => Ok(nfts.iter().map(AccessGrant::from).collect()),
+            Err((_, msg)) => Err(format!("NFT canister unavailable: {}", msg)),
+        }
+    }
+}
+
+impl AccessProvider for Icrc7Provider {
+    async fn grants(&self, _dataset_id: u32, user: Principal) -> Result<Vec<AccessGrant>, String> {
+        let accounts = vec![Account { owner: user, subaccount: None }];
+        let result: CallResult<(Vec<Nat>,)> = ic_cdk::call(self.canister_id, "icrc7_balance_of", (accounts,)).await;
+        match result {
+            Ok((balances,)) if balances.iter().any(|x| x.0.bits() > 0) => {
+                Ok(vec![AccessGrant::new(self.tier.clone(), self.columns.clone(), self.is_gdpr)])
+            },
+            Ok(_) => Ok(vec![]),
+            Err((_, msg)) => Err(format!("ICRC-7 canister unavailable: {}", msg)),
+        }
+    }
+}
+
+impl AccessProvider for AllowlistProvider {
+    async fn grants(&self, _dataset_id: u32, user: Principal) -> Result<Vec<AccessGrant>, String> {
+        Ok(self.entries
+            .iter()
+            .filter(|x| x.user == user)
+            .map(|x| AccessGrant::new(x.tier.clone(), x.columns.clone(), x.is_gdpr))
+            .collect())
+    }
+}
+
+impl AccessProvider for MockProvider {
+    async fn grants(&self, _dataset_id: u32, user: Principal) -> Result<Vec<AccessGrant>, String> {
+        if let Some(msg) = &self.failure {
+            return Err(msg.clone());
+        }
+        Ok(self.holdings
+            .iter()
+            .filter(|(x, _)| *x == user)
+            .flat_map(|(_, nfts)| nfts.iter().map(AccessGrant::from))
+            .collect())
+    }
+}
+
+// Datasets without a configured provider keep using the EXT canister the build was deployed with
+pub fn default_config() -> Option<AccessProviderConfig> {
+    option_env!("CANISTER_ID_fractional_NFT")
+        .and_then(|x| Principal::from_text(x).ok())
+        .map(|canister_id| AccessProviderConfig::ExtNft(ExtNftProvider { canister_id }))
+}
+
+pub async fn grants(config: &AccessProviderConfig, dataset_id: u32, user: Principal) -> Result<Vec<AccessGrant>, String> {
+    match config {
+        AccessProviderConfig::ExtNft(provider) => provider.grants(dataset_id, user).await,
+        AccessProviderConfig::Icrc7(provider) => provider.grants(dataset_id, user).await,
+        AccessProviderConfig::Allowlist(provider) => provider.grants(dataset_id, user).await,
+        AccessProviderConfig::Mock(provider) => provider.grants(dataset_id, user).await,
+    }
+}
+
+// Disabled NFTs are dropped; time-limited ones start their clock the first time the holder is seen with them.
+// Activations of NFTs the holder no longer has are cleared, so buying one back starts a new clock.
+pub fn activate(state: &mut StableState, dataset_id: u32, user: Principal, grants: Vec<AccessGrant>, now: u64) -> Vec<AccessGrant> {
+    let held: Vec<String> = grants.iter().map(|x| x.key()).collect();
+    if let Some(activations) = state.nft_activations.get_mut(&dataset_id) {
+        activations.retain(|x| x.user != user || held.contains(&x.nft));
+    }
+    grants
+        .into_iter()
+        .filter(|x| x.is_enabled)
+        .filter_map(|mut grant| {
+            if grant.time_limit_seconds == 0 {
+                return Some(grant);
+            }
+            let nft = grant.key();
+            let activations = state.nft_activations.entry(dataset_id).or_default();
+            let expire_at = match activations.iter().find(|x| x.user == user && x.nft == nft) {
+                Some(activation) => activation.expire_at,
+                None => {
+                    let expire_at = now + grant.time_limit_seconds as u64 * 1_000_000_000;
+                    activations.push(NftActivation { user, nft, activated_at: now, expire_at });
+                    expire_at
+                },
+            };
+            grant.expire_at = Some(expire_at);
+            (expire_at > now).then_some(grant)
+        })
+        .collect()
+}
+
+// The merged decision holds until the first of its grants expires
+pub fn merge(grants: &[AccessGrant], rule: AccessMergeRule) -> DatasetAccess {
+    let first = match grants.first() {
+        Some(first) => first,
+        None => return DatasetAccess::default(),
+    };
+    let (columns, is_gdpr) = match rule {
+        AccessMergeRule::MostPermissive => (
+            grants.iter().flat_map(|x| x.columns.iter().cloned()).unique().collect(),
+            grants.iter().all(|x| x.is_gdpr),
+        ),
+        AccessMergeRule::LeastPermissive => (
+            first.columns.iter().filter(|id| grants.iter().all(|x| x.columns.contains(id))).cloned().unique().collect(),
+            grants.iter().any(|x| x.is_gdpr),
+        ),
+    };
+    DatasetAccess {
+        columns,
+        is_gdpr,
+        tiers: grants.iter().filter_map(|x| x.tier.clone()).unique().collect(),
+        expire_at: grants.iter().filter_map(|x| x.expire_at).min(),
+        metered: false,
+    }
+}
+
+pub fn validate(dataset: &DatasetConfiguration, config: &AccessProviderConfig) -> Result<(), String> {
+    let columns: Vec<&Vec<u8>> = match config {
+        AccessProviderConfig::ExtNft(_) => vec![],
+        AccessProviderConfig::Icrc7(provider) => vec![&provider.columns],
+        AccessProviderConfig::Allowlist(provider) => provider.entries.iter().map(|x| &x.columns).collect(),
+        AccessProviderConfig::Mock(provider) => provider.holdings
+            .iter()
+            .flat_map(|(_, nfts)| nfts.iter().map(|x| &x.dimensionRestrictList))
+            .collect(),
+    };
+    if let Some(id) = columns.into_iter().flatten().find(|id| !dataset.dimensions.iter().any(|dim| dim.dimension_id == **id)) {
+        return Err(format!("Unknown dimension {}", id));
+    }
+    Ok(())
+}
+
+// Seconds; 0 turns caching off for the dataset
+pub fn validate_cache_ttl(ttl: u32) -> Result<(), String> {
+    if ttl > MAX_CACHE_TTL {
+        return Err(format!("Cache TTL cannot exceed {} seconds", MAX_CACHE_TTL));
+    }
+    Ok(())
+}
+
+pub fn cache_ttl(state: &StableState, dataset_id: u32) -> u64 {
+    state.access_cache_ttls.get(&dataset_id).cloned().unwrap_or(DEFAULT_CACHE_TTL) as u64 * 1_000_000_000
+}
+
+// Canisters allowed to push invalidations: the build's NFT and sales canisters, plus the dataset's own provider
+pub fn notifiers(state: &StableState, dataset_id: Option<u32>) -> Vec<Principal> {
+    let mut notifiers: Vec<Principal> = [option_env!("CANISTER_ID_fractional_NFT"), option_env!("CANISTER_ID_sales_contract")]
+        .iter()
+        .flatten()
+        .filter_map(|x| Principal::from_text(x).ok())
+        .collect();
+    match dataset_id.and_then(|id| state.access_providers.get(&id)) {
+        Some(AccessProviderConfig::ExtNft(provider)) => notifiers.push(provider.canister_id),
+        Some(AccessProviderConfig::Icrc7(provider)) => notifiers.push(provider.canister_id),
+        _ => {},
+    }
+    notifiers
+}
+
+impl AccessCache {
+    pub fn get(&self, dataset_id: u32, user: Principal, now: u64) -> Option<DatasetAccess> {
+        self.entries
+            .get(&(dataset_id, user))
+            .filter(|x| x.expire_at > now)
+            .map(|x| x.access.clone())
+    }
+
+    // Expired entries go first when full, then the ones closest to expiry
+    pub fn insert(&mut self, dataset_id: u32, user: Principal, access: DatasetAccess, expire_at: u64, now: u64) {
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            self.entries.retain(|_, x| x.expire_at > now);
+        }
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            if let Some(key) = self.entries.iter().min_by_key(|(_, x)| x.expire_at).map(|(key, _)| *key) {
+                self.entries.remove(&key);
+            }
+        }
+        self.entries.insert((dataset_id, user), AccessCacheEntry { access, expire_at });
+    }
+
+    pub fn invalidate(&mut self, dataset_id: Option<u32>, user: Option<Principal>) -> u32 {
+        let before = self.entries.len();
+        self.entries.retain(|(id, x), _| {
+            !(dataset_id.map(|d| d == *id).unwrap_or(true) && user.map(|u| u == *x).unwrap_or(true))
+        });
+        (before - self.entries.len()) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATASET: u32 = 4;
+    const HOUR: u64 = 3600 * 1_000_000_000;
+
+    fn holder() -> Principal {
+        Principal::from_slice(&[9; 29])
+    }
+
+    // A one-hour pass unless the test says otherwise
+    fn pass(token_index: Option<u32>) -> NftMetadata {
+        NftMetadata {
+            name: Some("pass".to_string()),
+            dataAssetId: DATASET,
+            isEnabled: true,
+            price: 10,
+            supply: 100,
+            timeLimitSeconds: 3600,
+            dimensionRestrictList: vec![1, 2],
+            isGdrpEnabled: true,
+            tokenIndex: token_index,
+        }
+    }
+
+    // The in-canister providers answer without awaiting anything, so one poll settles them
+    fn resolve<F: std::future::Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        match future.as_mut().poll(&mut context) {
+            std::task::Poll::Ready(output) => output,
+            std::task::Poll::Pending => panic!("provider awaited an inter-canister call"),
+        }
+    }
+
+    fn activate_at(state: &mut StableState, nfts: &[NftMetadata], now: u64) -> Vec<AccessGrant> {
+        activate(state, DATASET, holder(), nfts.iter().map(AccessGrant::from).collect(), now)
+    }
+
+    #[test]
+    fn time_limit_runs_from_first_sight() {
+        let mut state = StableState::default();
+        let granted = activate_at(&mut state, &[pass(Some(1))], HOUR);
+        assert_eq!(granted[0].expire_at, Some(2 * HOUR));
+        // Seeing the holder again does not restart the clock
+        assert_eq!(activate_at(&mut state, &[pass(Some(1))], 2 * HOUR - 1).len(), 1);
+        assert!(activate_at(&mut state, &[pass(Some(1))], 2 * HOUR).is_empty());
+    }
+
+    #[test]
+    fn repurchase_starts_a_new_clock() {
+        let mut state = StableState::default();
+        activate_at(&mut state, &[pass(Some(1))], 0);
+        assert!(activate_at(&mut state, &[pass(Some(1))], HOUR).is_empty());
+        // A new token of the same tier while still holding the expired one
+        let granted = activate_at(&mut state, &[pass(Some(1)), pass(Some(2))], 2 * HOUR);
+        assert_eq!(granted.len(), 1);
+        assert_eq!(granted[0].expire_at, Some(3 * HOUR));
+    }
+
+    // Without token indexes, selling the pass clears its activation
+    #[test]
+    fn lapsed_holdings_are_forgotten() {
+        let mut state = StableState::default();
+        activate_at(&mut state, &[pass(None)], 0);
+        assert!(activate_at(&mut state, &[pass(None)], HOUR).is_empty());
+        activate_at(&mut state, &[], HOUR);
+        assert!(state.nft_activations[&DATASET].is_empty());
+        assert_eq!(activate_at(&mut state, &[pass(None)], 2 * HOUR)[0].expire_at, Some(3 * HOUR));
+    }
+
+    #[test]
+    fn disabled_nfts_grant_nothing() {
+        let mut state = StableState::default();
+        let disabled = NftMetadata { isEnabled: false, timeLimitSeconds: 0, ..pass(None) };
+        assert!(activate_at(&mut state, &[disabled], 0).is_empty());
+        let unlimited = NftMetadata { timeLimitSeconds: 0, ..pass(None) };
+        let granted = activate_at(&mut state, &[unlimited], 0);
+        assert_eq!(granted[0].expire_at, None);
+        assert!(state.nft_activations.get(&DATASET).is_none_or(|x| x.is_empty()));
+    }
+
+    fn grant(tier: &str, columns: Vec<u8>, is_gdpr: bool, expire_at: Option<u64>) -> AccessGrant {
+        AccessGrant { expire_at, ..AccessGrant::new(Some(tier.to_string()), columns, is_gdpr) }
+    }
+
+    #[test]
+    fn most_permissive_unions_columns() {
+        let grants = [grant("basic", vec![1, 2], true, Some(HOUR)), grant("pro", vec![2, 3], false, None)];
+        let access = merge(&grants, AccessMergeRule::MostPermissive);
+        assert_eq!(access.columns, vec![1, 2, 3]);
+        assert!(!access.is_gdpr);
+        assert_eq!(access.tiers, vec!["basic".to_string(), "pro".to_string()]);
+        assert_eq!(access.expire_at, Some(HOUR));
+    }
+
+    #[test]
+    fn least_permissive_intersects_columns() {
+        let grants = [grant("basic", vec![1, 2], true, None), grant("pro", vec![2, 3], false, None)];
+        let access = merge(&grants, AccessMergeRule::LeastPermissive);
+        assert_eq!(access.columns, vec![2]);
+        assert!(access.is_gdpr);
+        assert_eq!(access.expire_at, None);
+        assert_eq!(merge(&[], AccessMergeRule::LeastPermissive), DatasetAccess::default());
+    }
+
+    fn other() -> Principal {
+        Principal::from_slice(&[8; 29])
+    }
+
+    #[test]
+    fn allowlist_grants_only_listed_users() {
+        let config = AccessProviderConfig::Allowlist(AllowlistProvider {
+            entries: vec![
+                AllowlistEntry { user: holder(), tier: Some("staff".to_string()), columns: vec![1], is_gdpr: false },
+                AllowlistEntry { user: other(), tier: None, columns: vec![2], is_gdpr: true },
+            ],
+        });
+        let granted = resolve(grants(&config, DATASET, holder())).unwrap();
+        assert_eq!(granted, vec![AccessGrant::new(Some("staff".to_string()), vec![1], false)]);
+        assert!(resolve(grants(&config, DATASET, Principal::anonymous())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn mock_answers_like_the_nft_canister_or_fails() {
+        let mut mock = MockProvider { holdings: vec![(holder(), vec![pass(Some(3))])], failure: None };
+        let granted = resolve(grants(&AccessProviderConfig::Mock(mock.clone()), DATASET, holder())).unwrap();
+        assert_eq!(granted, vec![AccessGrant::from(&pass(Some(3)))]);
+        assert_eq!((granted[0].token, granted[0].time_limit_seconds), (Some(3), 3600));
+        assert!(resolve(grants(&AccessProviderConfig::Mock(mock.clone()), DATASET, other())).unwrap().is_empty());
+        mock.failure = Some("down".to_string());
+        assert_eq!(resolve(grants(&AccessProviderConfig::Mock(mock), DATASET, holder())), Err("down".to_string()));
+    }
+
+    fn cached(columns: Vec<u8>) -> DatasetAccess {
+        DatasetAccess { columns, ..DatasetAccess::default() }
+    }
+
+    #[test]
+    fn cached_access_expires_with_its_ttl() {
+        let mut state = StableState::default();
+        assert_eq!(cache_ttl(&state, DATASET), DEFAULT_CACHE_TTL as u64 * 1_000_000_000);
+        state.access_cache_ttls.insert(DATASET, 60);
+        let ttl = cache_ttl(&state, DATASET);
+        let mut cache = AccessCache::default();
+        cache.insert(DATASET, holder(), cached(vec![1]), HOUR + ttl, HOUR);
+        assert_eq!(cache.get(DATASET, holder(), HOUR + ttl - 1), Some(cached(vec![1])));
+        assert_eq!(cache.get(DATASET, holder(), HOUR + ttl), None);
+        assert!(validate_cache_ttl(MAX_CACHE_TTL).is_ok() && validate_cache_ttl(MAX_CACHE_TTL + 1).is_err());
+    }
+
+    #[test]
+    fn pushed_invalidations_drop_matching_entries() {
+        let mut cache = AccessCache::default();
+        for (dataset_id, user) in [(DATASET, holder()), (DATASET, other()), (DATASET + 1, holder())] {
+            cache.insert(dataset_id, user, cached(vec![1]), HOUR, 0);
+        }
+        assert_eq!(cache.invalidate(Some(DATASET), Some(holder())), 1);
+        assert_eq!(cache.get(DATASET, other(), 0), Some(cached(vec![1])));
+        assert_eq!(cache.invalidate(None, Some(holder())), 1);
+        assert_eq!(cache.invalidate(Some(DATASET), None), 1);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn full_cache_drops_expired_entries_first() {
+        let mut cache = AccessCache::default();
+        for i in 0..MAX_CACHE_ENTRIES {
+            let user = Principal::from_slice(&(i as u32).to_be_bytes());
+            cache.insert(DATASET, user, cached(vec![1]), if i == 0 { 10 } else { HOUR + i as u64 }, 0);
+        }
+        cache.insert(DATASET, holder(), cached(vec![2]), HOUR, 20);
+        assert_eq!(cache.entries.len(), MAX_CACHE_ENTRIES);
+        assert!(cache.get(DATASET, Principal::from_slice(&0u32.to_be_bytes()), 0).is_none());
+        // Nothing expired: the entry closest to expiry makes room
+        cache.insert(DATASET, other(), cached(vec![3]), 2 * HOUR, 20);
+        assert!(cache.get(DATASET, holder(), 20).is_none());
+        assert_eq!(cache.get(DATASET, other(), 20), Some(cached(vec![3])));
+    }
+
+    #[test]
+    fn provider_canisters_may_push_invalidations() {
+        let mut state = StableState::default();
+        let canister_id = Principal::from_slice(&[7; 10]);
+        state.access_providers.insert(DATASET, AccessProviderConfig::Icrc7(Icrc7Provider { canister_id, tier: None, columns: vec![1], is_gdpr: false }));
+        assert!(notifiers(&state, Some(DATASET)).contains(&canister_id));
+        assert!(!notifiers(&state, Some(DATASET + 1)).contains(&canister_id));
+        assert!(!notifiers(&state, None).contains(&canister_id));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::types::*;
+use itertools::Itertools;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// Previous queries of the same consumer compared against each new one
+pub const AUDIT_WINDOW: usize = 100;
+
+// Rows a filter pair excludes, gathered in one pass over the visible entries. A query excludes
+// the union of its pairs, so each earlier query costs the rows it excluded, not the dataset.
+struct FilterIndex {
+    rows: HashMap<(u8, String), Vec<usize>>,
+}
+
+// Debug keeps attributes and metrics apart where Display would not
+fn filter_key(dimension_id: u8, value: &Value) -> (u8, String) {
+    (dimension_id, format!("{:?}", value))
+}
+
+impl FilterIndex {
+    fn new<'a>(entries: &[DatasetEntry], queries: impl Iterator<Item = &'a QueryInput>) -> Self {
+        let mut rows: HashMap<(u8, String), Vec<usize>> = queries
+            .flat_map(|query| query.filters.iter())
+            .map(|(id, value)| (filter_key(*id, value), vec![]))
+            .collect();
+        let dimensions: HashSet<u8> = rows.keys().map(|x| x.0).collect();
+        for (i, entry) in entries.iter().enumerate() {
+            for val in entry.values.iter().filter(|val| dimensions.contains(&val.dimension_id)) {
+                if let Some(matched) = rows.get_mut(&filter_key(val.dimension_id, &val.value)) {
+                    // An entry holding the same pair twice is still one row
+                    if matched.last() != Some(&i) { matched.push(i); }
+                }
+            }
+        }
+        FilterIndex { rows }
+    }
+
+    // Same selection as the filter stage of `fetch_analytics`, given as the rows it leaves out
+    fn excluded(&self, filters: &[(u8, Value)]) -> BTreeSet<usize> {
+        filters
+            .iter()
+            .filter_map(|(id, value)| self.rows.get(&filter_key(*id, value)))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+fn group_key(entry: &DatasetEntry, attributes: &[u8]) -> String {
+    entry.values
+        .iter()
+        .filter(|val| attributes.contains(&val.dimension_id))
+        .map(|val| val.value.to_string())
+        .join("--")
+}
+
+// A non-empty difference smaller than k between two released selections isolates those records.
+fn isolates(entries: &[DatasetEntry], current: &BTreeSet<usize>, previous: &BTreeSet<usize>, attributes: &[u8], k: u32) -> bool {
+    let differing: Vec<&DatasetEntry> = current
+        .symmetric_difference(previous)
+        .map(|i| &entries[*i])
+        .collect();
+    if differing.is_empty() {
+        return false;
+    }
+    if (differing.len() as u32) < k {
+        return true;
+    }
+    differing
+        .iter()
+        .counts_by(|entry| group_key(entry, attributes))
+        .values()
+        .any(|count| (*count as u32) < k)
+}
+
+// Returns the reason and the earlier query involved, if the new query enables re-identification.
+pub fn check(entries: &[DatasetEntry], query: &QueryInput, history: &[(u32, QueryInput)], k: u32) -> Option<(String, Option<u32>)> {
+    let index = FilterIndex::new(entries, std::iter::once(query).chain(history.iter().map(|x| &x.1)));
+    let current = index.excluded(&query.filters);
+    let excluded = current.len() as u32;
+    if excluded > 0 && excluded < k {
+        return Some((format!("Filters exclude only {} record(s), fewer than k = {}", excluded, k), None));
+    }
+    for (id, previous) in history.iter() {
+        let attributes = if previous.attributes == query.attributes { &query.attributes[..] } else { &[] };
+        let previous_set = index.excluded(&previous.filters);
+        if isolates(entries, &current, &previous_set, attributes, k) {
+            return Some((format!("Result overlaps query {} up to fewer than k = {} record(s)", id, k), Some(*id)));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    const REGION: u8 = 1;
+    const JOB: u8 = 2;
+
+    fn person(id: u32, region: &str, job: &str) -> DatasetEntry {
+        DatasetEntry {
+            id: RecordKey::Id(id),
+            producer: Principal::anonymous(),
+            values: vec![
+                DatasetValue { dimension_id: REGION, value: Value::Attribute(region.to_string()) },
+                DatasetValue { dimension_id: JOB, value: Value::Attribute(job.to_string()) },
+            ],
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    // Five in the north, one of them the only CEO; five in the south, one of them the only pilot
+    fn staff() -> Vec<DatasetEntry> {
+        let north = ["ceo", "clerk", "clerk", "clerk", "clerk"].iter().map(|job| ("N", *job));
+        let south = ["pilot", "nurse", "nurse", "nurse", "nurse"].iter().map(|job| ("S", *job));
+        north.chain(south).enumerate().map(|(i, (region, job))| person(i as u32, region, job)).collect()
+    }
+
+    fn query(attributes: Vec<u8>, filters: Vec<(u8, &str)>) -> QueryInput {
+        QueryInput {
+            dataset_id: 1,
+            attributes,
+            metrics: vec![],
+            filters: filters.into_iter().map(|(id, x)| (id, Value::Attribute(x.to_string()))).collect(),
+        }
+    }
+
+    #[test]
+    fn unfiltered_queries_pass() {
+        assert_eq!(check(&staff(), &query(vec![REGION], vec![]), &[], 3), None);
+    }
+
+    #[test]
+    fn excluding_fewer_than_k_records_is_flagged() {
+        let finding = check(&staff(), &query(vec![REGION], vec![(JOB, "ceo")]), &[], 3);
+        assert_eq!(finding.map(|(_, id)| id), Some(None));
+    }
+
+    // Each query excludes enough records on its own; together they single out the CEO
+    #[test]
+    fn differencing_two_queries_is_flagged() {
+        let history = vec![(7, query(vec![], vec![(REGION, "S")]))];
+        let current = query(vec![], vec![(REGION, "S"), (JOB, "ceo")]);
+        let finding = check(&staff(), &current, &history, 3);
+        assert_eq!(finding.map(|(_, id)| id), Some(Some(7)));
+    }
+
+    #[test]
+    fn metric_and_attribute_filters_are_told_apart() {
+        let mut entries = staff();
+        entries[0].values.push(DatasetValue { dimension_id: 3, value: Value::Metric(5) });
+        let index = FilterIndex::new(&entries, std::iter::empty());
+        assert!(index.excluded(&[(3, Value::Metric(5))]).is_empty());
+        let filters = vec![(3, Value::Metric(5)), (3, Value::Attribute("5".to_string())), (REGION, Value::Attribute("N".to_string()))];
+        let query = QueryInput { dataset_id: 1, attributes: vec![], metrics: vec![], filters };
+        let index = FilterIndex::new(&entries, std::iter::once(&query));
+        assert_eq!(index.excluded(&query.filters[..2]), BTreeSet::from([0]));
+        assert_eq!(index.excluded(&query.filters), (0..5).collect());
+    }
+
+    // The five southern records differ, but grouped by job the pilot stands alone
+    #[test]
+    fn small_groups_in_the_difference_count_only_under_the_same_grouping() {
+        let history = vec![(7, query(vec![JOB], vec![]))];
+        assert_eq!(check(&staff(), &query(vec![JOB], vec![(REGION, "S")]), &history, 3).map(|(_, id)| id), Some(Some(7)));
+        assert_eq!(check(&staff(), &query(vec![REGION], vec![(REGION, "S")]), &history, 3), None);
+    }
+}
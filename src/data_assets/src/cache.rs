@@ -0,0 +1,196 @@
+use crate::policy::EntryPolicy;
+use crate::privacy::DisclosureControl;
+use crate::types::*;
+use itertools::Itertools;
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        CacheLimits {
+            max_bytes: 32 * 1024 * 1024,
+            max_entries: 1_000,
+        }
+    }
+}
+
+// Attribute order drives grouping and is kept; metric and filter order do not change the result.
+pub fn cache_key(query: &QueryInput, authorized: &[u8], control: &DisclosureControl, entry_policy: &EntryPolicy, version: u64) -> String {
+    let metrics = query.metrics.iter().sorted().dedup().join(",");
+    let filters = query.filters
+        .iter()
+        .map(|(id, value)| format!("{}={:?}", id, value))
+        .sorted()
+        .dedup()
+        .join(",");
+    let authorized = authorized.iter().sorted().dedup().join(",");
+    format!(
+        "{}@{}|a:{}|m:{}|f:{}|auth:{}|gdpr:{:?}|p:{:?}|mask:{:?}|rows:{:?}",
+        query.dataset_id,
+        version,
+        query.attributes.iter().join(","),
+        metrics,
+        filters,
+        authorized,
+        control,
+        entry_policy.purposes,
+        entry_policy.masking.masks,
+        entry_policy.rows,
+    )
+}
+
+impl QueryCache {
+    pub fn get(&mut self, key: &str) -> Option<AnalyticsSuperType> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = self.tick;
+                self.hits += 1;
+                Some(entry.result.clone())
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    pub fn insert(&mut self, key: String, dataset_id: u32, result: AnalyticsSuperType, limits: &CacheLimits) {
+        let size_bytes = candid::encode_one(&result).map(|x| x.len() as u64).unwrap_or(0) + key.len() as u64;
+        if size_bytes > limits.max_bytes || limits.max_entries == 0 { return; }
+        self.remove(&key);
+        self.tick += 1;
+        self.size_bytes += size_bytes;
+        self.entries.insert(key, CacheEntry { dataset_id, result, size_bytes, last_used: self.tick });
+        self.evict(limits);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size_bytes -= entry.size_bytes;
+        }
+    }
+
+    // Least recently used entries go first
+    pub fn evict(&mut self, limits: &CacheLimits) {
+        while self.size_bytes > limits.max_bytes || self.entries.len() > limits.max_entries as usize {
+            let oldest = self.entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => {
+                    self.remove(&key);
+                    self.evictions += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    pub fn invalidate_dataset(&mut self, dataset_id: u32) {
+        let keys: Vec<String> = self.entries
+            .iter()
+            .filter(|(_, entry)| entry.dataset_id == dataset_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys { self.remove(&key); }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size_bytes = 0;
+    }
+
+    pub fn stats(&self, limits: &CacheLimits) -> QueryCacheStats {
+        QueryCacheStats {
+            entries: self.entries.len() as u32,
+            size_bytes: self.size_bytes,
+            limits: limits.clone(),
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::export::Principal;
+
+    fn result(count: u32) -> AnalyticsSuperType {
+        AnalyticsSuperType { analytics: vec![], counts: (count, 0, 0, 0, 0) }
+    }
+
+    fn limits(max_entries: u32) -> CacheLimits {
+        CacheLimits { max_bytes: 1024 * 1024, max_entries }
+    }
+
+    fn cached_bytes(cache: &QueryCache) -> u64 {
+        cache.entries.values().map(|entry| entry.size_bytes).sum()
+    }
+
+    fn query(attributes: Vec<u8>, metrics: Vec<u8>, filters: Vec<(u8, Value)>) -> QueryInput {
+        QueryInput { dataset_id: 1, attributes, metrics, filters }
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_first() {
+        let mut cache = QueryCache::default();
+        let limits = limits(2);
+        cache.insert("a".to_string(), 1, result(1), &limits);
+        cache.insert("b".to_string(), 1, result(2), &limits);
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_string(), 1, result(3), &limits);
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("a"), Some(result(1)));
+        assert_eq!(cache.get("c"), Some(result(3)));
+        assert_eq!((cache.hits, cache.misses, cache.evictions), (3, 1, 1));
+    }
+
+    #[test]
+    fn byte_size_follows_the_entries() {
+        let mut cache = QueryCache::default();
+        let limits = limits(10);
+        cache.insert("a".to_string(), 1, result(1), &limits);
+        cache.insert("b".to_string(), 2, result(2), &limits);
+        let two = cache.size_bytes;
+        assert!(two > 0 && two == cached_bytes(&cache));
+        // Replacing a key does not count it twice
+        cache.insert("a".to_string(), 1, result(4), &limits);
+        assert_eq!(cache.size_bytes, two);
+        cache.invalidate_dataset(1);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.size_bytes, cached_bytes(&cache));
+        cache.clear();
+        assert_eq!(cache.size_bytes, 0);
+    }
+
+    #[test]
+    fn byte_limit_evicts_and_oversized_results_are_skipped() {
+        let mut cache = QueryCache::default();
+        cache.insert("a".to_string(), 1, result(1), &limits(10));
+        let one = cache.size_bytes;
+        let tight = CacheLimits { max_bytes: one * 2 - 1, max_entries: 10 };
+        cache.insert("b".to_string(), 1, result(2), &tight);
+        assert_eq!(cache.entries.keys().collect::<Vec<&String>>(), vec!["b"]);
+        assert_eq!(cache.size_bytes, one);
+        let oversized = CacheLimits { max_bytes: one - 1, max_entries: 10 };
+        cache.insert("c".to_string(), 1, result(3), &oversized);
+        assert!(!cache.entries.contains_key("c"));
+    }
+
+    #[test]
+    fn keys_change_with_the_dataset_version() {
+        let entry_policy = EntryPolicy::new(&StableState::default(), 1, Principal::anonymous(), &[], ConsentPurpose::Analytics, 0);
+        let control = DisclosureControl::default();
+        let key = |query: &QueryInput, version| cache_key(query, &[1, 2, 3], &control, &entry_policy, version);
+        let base = query(vec![1, 2], vec![3, 4], vec![(1, Value::Attribute("a".to_string())), (2, Value::Metric(1))]);
+        assert_ne!(key(&base, 1), key(&base, 2));
+        // Metric and filter order do not matter, attribute order does
+        let reordered = query(vec![1, 2], vec![4, 3], vec![(2, Value::Metric(1)), (1, Value::Attribute("a".to_string()))]);
+        assert_eq!(key(&base, 1), key(&reordered, 1));
+        assert_ne!(key(&base, 1), key(&query(vec![2, 1], vec![3, 4], base.filters.clone()), 1));
+        // A metric filter is not the attribute with the same text
+        assert_ne!(key(&query(vec![], vec![], vec![(2, Value::Metric(1))]), 1), key(&query(vec![], vec![], vec![(2, Value::Attribute("1".to_string()))]), 1));
+    }
+}
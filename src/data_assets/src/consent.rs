@@ -0,0 +1,125 @@
+use crate::types::*;
+use ic_cdk::export::Principal;
+use itertools::Itertools;
+use std::collections::HashMap;
+
+// Only entries keyed by a person need consent; entries keyed by an id have no data subject.
+pub fn is_consented(consents: Option<&HashMap<Principal, Vec<ConsentPurpose>>>, entry: &DatasetEntry, purposes: &[ConsentPurpose]) -> bool {
+    match entry.id {
+        RecordKey::Id(_) => true,
+        RecordKey::User(user) => consents
+            .and_then(|x| x.get(&user))
+            .map(|granted| purposes.iter().all(|purpose| granted.contains(purpose)))
+            .unwrap_or(false),
+    }
+}
+
+pub fn filter_entries(state: &StableState, dataset_id: u32, entries: &[DatasetEntry], purposes: &[ConsentPurpose]) -> Vec<DatasetEntry> {
+    let consents = state.consents.get(&dataset_id);
+    entries
+        .iter()
+        .filter(|entry| is_consented(consents, entry, purposes))
+        .cloned()
+        .collect()
+}
+
+// The operation purpose plus whatever the held tiers are declared to be used for
+pub fn required_purposes(state: &StableState, dataset_id: u32, tiers: &[String], purpose: ConsentPurpose) -> Vec<ConsentPurpose> {
+    let declared = state.tier_purposes
+        .get(&dataset_id)
+        .map(|x| {
+            x.iter()
+                .filter(|(tier, _)| tiers.contains(tier))
+                .flat_map(|(_, purposes)| purposes.iter().cloned())
+                .collect::<Vec<ConsentPurpose>>()
+        })
+        .unwrap_or_default();
+    std::iter::once(purpose).chain(declared).sorted().dedup().collect()
+}
+
+pub fn update(state: &mut StableState, subject: Principal, dataset_id: u32, purpose: ConsentPurpose, mode: UpdateMode, now: u64) -> bool {
+    let granted = state.consents.entry(dataset_id).or_default().entry(subject).or_default();
+    let changed = match mode {
+        UpdateMode::Add if !granted.contains(&purpose) => {
+            granted.push(purpose);
+            granted.sort();
+            true
+        },
+        UpdateMode::Remove if granted.contains(&purpose) => {
+            granted.retain(|x| *x != purpose);
+            true
+        },
+        _ => false,
+    };
+    if changed {
+        let event = ConsentEvent { subject, dataset_id, purpose, mode, timestamp: now };
+        state.consent_log.entry(dataset_id).or_default().push(event);
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::EntryPolicy;
+
+    fn person(seed: u8) -> Principal {
+        Principal::from_slice(&[seed; 29])
+    }
+
+    fn entry(id: RecordKey) -> DatasetEntry {
+        DatasetEntry { id, producer: person(9), values: vec![], created_at: 0, updated_at: 0 }
+    }
+
+    // Person 1 consents to analytics only, person 2 to both, person 3 to nothing
+    fn state() -> StableState {
+        let mut state = StableState::default();
+        state.dataset_values.insert(1, vec![
+            entry(RecordKey::User(person(1))),
+            entry(RecordKey::User(person(2))),
+            entry(RecordKey::User(person(3))),
+            entry(RecordKey::Id(7)),
+        ]);
+        update(&mut state, person(1), 1, ConsentPurpose::Analytics, UpdateMode::Add, 0);
+        update(&mut state, person(2), 1, ConsentPurpose::Analytics, UpdateMode::Add, 0);
+        update(&mut state, person(2), 1, ConsentPurpose::Download, UpdateMode::Add, 0);
+        state
+    }
+
+    fn visible(state: &StableState, tiers: &[String], purpose: ConsentPurpose) -> Vec<RecordKey> {
+        EntryPolicy::new(state, 1, person(8), tiers, purpose, 0)
+            .visible_entries(state, 1)
+            .into_iter()
+            .map(|x| x.id)
+            .collect()
+    }
+
+    #[test]
+    fn downloads_and_analytics_need_their_own_consent() {
+        let state = state();
+        assert_eq!(visible(&state, &[], ConsentPurpose::Analytics), vec![RecordKey::User(person(1)), RecordKey::User(person(2)), RecordKey::Id(7)]);
+        assert_eq!(visible(&state, &[], ConsentPurpose::Download), vec![RecordKey::User(person(2)), RecordKey::Id(7)]);
+    }
+
+    #[test]
+    fn tier_purposes_add_to_the_operation() {
+        let mut state = state();
+        state.tier_purposes.insert(1, vec![("resale".to_string(), vec![ConsentPurpose::Resale])]);
+        let tiers = vec!["resale".to_string()];
+        assert_eq!(required_purposes(&state, 1, &tiers, ConsentPurpose::Analytics), vec![ConsentPurpose::Analytics, ConsentPurpose::Resale]);
+        assert_eq!(visible(&state, &tiers, ConsentPurpose::Analytics), vec![RecordKey::Id(7)]);
+        update(&mut state, person(1), 1, ConsentPurpose::Resale, UpdateMode::Add, 0);
+        assert_eq!(visible(&state, &tiers, ConsentPurpose::Analytics), vec![RecordKey::User(person(1)), RecordKey::Id(7)]);
+    }
+
+    #[test]
+    fn withdrawal_hides_entries_and_is_logged_once() {
+        let mut state = state();
+        assert!(update(&mut state, person(2), 1, ConsentPurpose::Download, UpdateMode::Remove, 5));
+        assert!(!update(&mut state, person(2), 1, ConsentPurpose::Download, UpdateMode::Remove, 6));
+        assert_eq!(visible(&state, &[], ConsentPurpose::Download), vec![RecordKey::Id(7)]);
+        let last = state.consent_log[&1].last().unwrap();
+        assert_eq!((last.subject, last.purpose, last.mode.clone(), last.timestamp), (person(2), ConsentPurpose::Download, UpdateMode::Remove, 5));
+        assert_eq!(state.consent_log[&1].len(), 4);
+    }
+}
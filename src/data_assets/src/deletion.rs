@@ -0,0 +1,174 @@
+use crate::types::*;
+use ic_cdk::export::Principal;
+
+// Time an owner has to restore a deleted dataset before everything about it is purged
+pub const GRACE_PERIOD: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Ids are never reused: the deletion record stays behind as a tombstone.
+pub fn next_dataset_id(state: &StableState) -> u32 {
+    let highest = state.datasets
+        .keys()
+        .chain(state.dataset_deletions.keys())
+        .cloned()
+        .max()
+        .unwrap_or(0);
+    std::cmp::max(state.next_dataset_id, highest) + 1
+}
+
+// Deactivates the dataset and starts the grace period
+pub fn schedule(state: &mut StableState, dataset_id: u32, requested_by: Principal, now: u64) -> Result<DatasetDeletion, String> {
+    let config = state.datasets.get_mut(&dataset_id).ok_or("Dataset not found")?;
+    config.is_active = false;
+    config.updated_at = now;
+    let deletion = DatasetDeletion {
+        dataset_id,
+        name: config.name.clone(),
+        requested_by,
+        requested_at: now,
+        purge_at: now + GRACE_PERIOD,
+        purged_at: None,
+    };
+    state.dataset_deletions.insert(dataset_id, deletion.clone());
+    Ok(deletion)
+}
+
+// Only possible before the purge; the data was never touched until then
+pub fn restore(state: &mut StableState, dataset_id: u32, now: u64) -> Result<(), String> {
+    match state.dataset_deletions.get(&dataset_id) {
+        Some(deletion) if deletion.purged_at.is_none() => {},
+        _ => return Err("Dataset is not pending deletion".to_string()),
+    }
+    state.dataset_deletions.remove(&dataset_id);
+    if let Some(config) = state.datasets.get_mut(&dataset_id) {
+        config.is_active = true;
+        config.updated_at = now;
+    }
+    Ok(())
+}
+
+pub fn due(state: &StableState, now: u64) -> Option<u32> {
+    state.dataset_deletions
+        .values()
+        .filter(|x| x.purged_at.is_none() && x.purge_at <= now)
+        .map(|x| x.dataset_id)
+        .min()
+}
+
+// Everything held about the dataset except its tombstone and the certified erasure log.
+pub fn cascade(state: &mut StableState, dataset_id: u32, now: u64) {
+    state.datasets.remove(&dataset_id);
+    state.dataset_values.remove(&dataset_id);
+    state.dataset_versions.remove(&dataset_id);
+    state.dataset_producers.remove(&dataset_id);
+    for datasets in state.dataset_owners.values_mut() {
+        datasets.retain(|x| *x != dataset_id);
+    }
+    state.dataset_owners.retain(|_, datasets| !datasets.is_empty());
+    state.queries.retain(|_, x| x.query_meta.dataset_id != dataset_id);
+    state.query_templates.remove(&dataset_id);
+    state.materialized_views.remove(&dataset_id);
+    state.privacy_policies.remove(&dataset_id);
+    state.privacy_budgets.remove(&dataset_id);
+    state.audit_alerts.remove(&dataset_id);
+    state.consents.remove(&dataset_id);
+    state.tier_purposes.remove(&dataset_id);
+    state.consent_log.remove(&dataset_id);
+    state.masking_policies.remove(&dataset_id);
+    state.row_policies.remove(&dataset_id);
+    state.retention_policies.remove(&dataset_id);
+    state.purge_log.remove(&dataset_id);
+    state.dataset_roles.remove(&dataset_id);
+    state.role_history.remove(&dataset_id);
+    state.owner_quorums.remove(&dataset_id);
+    state.owner_proposals.remove(&dataset_id);
+    state.ownership_offers.remove(&dataset_id);
+    state.access_providers.remove(&dataset_id);
+    state.access_cache_ttls.remove(&dataset_id);
+    state.access_merge_rules.remove(&dataset_id);
+    state.nft_activations.remove(&dataset_id);
+    state.direct_grants.remove(&dataset_id);
+    if let Some(deletion) = state.dataset_deletions.get_mut(&dataset_id) {
+        deletion.purged_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    fn config() -> DatasetConfiguration {
+        DatasetConfiguration {
+            name: "survey".to_string(),
+            asset_id: String::new(),
+            description: String::new(),
+            jupyter_notebook: None,
+            dimensions: vec![],
+            is_active: true,
+            category: vec![],
+            official_templates: vec![],
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn entry() -> DatasetEntry {
+        DatasetEntry { id: RecordKey::Id(1), producer: owner(), values: vec![], created_at: 0, updated_at: 0 }
+    }
+
+    // Datasets 1 and 2 of the same owner, both with entries
+    fn state() -> StableState {
+        let mut state = StableState::default();
+        for id in [1, 2] {
+            state.datasets.insert(id, config());
+            state.dataset_values.insert(id, vec![entry()]);
+            state.consents.insert(id, Default::default());
+        }
+        state.dataset_owners.insert(owner(), vec![1, 2]);
+        state.next_dataset_id = 2;
+        state
+    }
+
+    #[test]
+    fn soft_delete_keeps_the_data_until_due() {
+        let mut state = state();
+        let deletion = schedule(&mut state, 1, owner(), 10).unwrap();
+        assert_eq!(deletion.purge_at, 10 + GRACE_PERIOD);
+        assert!(!state.datasets[&1].is_active);
+        assert_eq!(state.dataset_values[&1].len(), 1);
+        assert_eq!(due(&state, 9 + GRACE_PERIOD), None);
+        assert_eq!(due(&state, 10 + GRACE_PERIOD), Some(1));
+        assert!(schedule(&mut state, 3, owner(), 10).is_err());
+    }
+
+    #[test]
+    fn restore_reactivates_before_the_purge() {
+        let mut state = state();
+        schedule(&mut state, 1, owner(), 10).unwrap();
+        restore(&mut state, 1, 20).unwrap();
+        assert!(state.datasets[&1].is_active);
+        assert!(state.dataset_deletions.is_empty());
+        assert_eq!(due(&state, 10 + GRACE_PERIOD), None);
+        assert!(restore(&mut state, 1, 30).is_err());
+    }
+
+    #[test]
+    fn cascade_purges_only_the_dataset_and_leaves_a_tombstone() {
+        let mut state = state();
+        schedule(&mut state, 1, owner(), 10).unwrap();
+        cascade(&mut state, 1, 10 + GRACE_PERIOD);
+        assert!(!state.datasets.contains_key(&1) && !state.dataset_values.contains_key(&1) && !state.consents.contains_key(&1));
+        assert_eq!(state.dataset_owners[&owner()], vec![2]);
+        assert_eq!(state.dataset_values[&2].len(), 1);
+        assert_eq!(state.dataset_deletions[&1].purged_at, Some(10 + GRACE_PERIOD));
+        // Purged datasets can neither come back nor be purged again, and their id is not reused
+        assert!(restore(&mut state, 1, 20 + GRACE_PERIOD).is_err());
+        assert_eq!(due(&state, 20 + GRACE_PERIOD), None);
+        state.next_dataset_id = 0;
+        state.datasets.remove(&2);
+        assert_eq!(next_dataset_id(&state), 2);
+    }
+}
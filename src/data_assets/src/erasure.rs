@@ -0,0 +1,170 @@
+use crate::pseudonym::hmac_sha256;
+use crate::types::*;
+use ic_cdk::export::Principal;
+use sha2::{Digest, Sha256};
+
+// Erasure records form a hash chain whose head is set as the canister certified data, so a
+// receipt can be checked against the subnet signature. Only digests of removed data are kept,
+// and only the links of the chain are public.
+
+// Keyed with the canister salt, so nobody can hash a principal and look it up in the log
+pub fn subject_digest(key: &[u8], user: &Principal) -> Vec<u8> {
+    hmac_sha256(key, &[b"erasure:".as_slice(), user.as_slice()].concat())
+}
+
+pub fn entries_digest(entries: &[DatasetEntry]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for entry in entries.iter() {
+        hasher.update(candid::encode_one(entry).unwrap_or_default());
+    }
+    hasher.finalize().to_vec()
+}
+
+fn content_digest(record: &ErasureRecord) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(record.receipt_id.to_be_bytes());
+    hasher.update(&record.subject_digest);
+    hasher.update(record.timestamp.to_be_bytes());
+    for item in record.items.iter() {
+        hasher.update(item.dataset_id.to_be_bytes());
+        hasher.update(item.removed_entries.to_be_bytes());
+        hasher.update(&item.entries_digest);
+    }
+    hasher.finalize().to_vec()
+}
+
+fn link_hash(previous_hash: &[u8], content_digest: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(previous_hash);
+    hasher.update(content_digest);
+    hasher.finalize().to_vec()
+}
+
+fn record_hash(record: &ErasureRecord) -> Vec<u8> {
+    link_hash(&record.previous_hash, &content_digest(record))
+}
+
+// Enough to walk the chain from a receipt up to the certified head, without the content
+pub fn public_link(record: &ErasureRecord) -> ErasureLink {
+    ErasureLink {
+        receipt_id: record.receipt_id,
+        previous_hash: record.previous_hash.clone(),
+        content_digest: content_digest(record),
+        hash: record.hash.clone(),
+    }
+}
+
+// What the owners of one dataset see: only the items erased from it
+pub fn for_dataset(record: &ErasureRecord, dataset_id: u32) -> Option<ErasureRecord> {
+    let items: Vec<ErasureItem> = record.items.iter().filter(|x| x.dataset_id == dataset_id).cloned().collect();
+    if items.is_empty() {
+        return None;
+    }
+    Some(ErasureRecord { items, ..record.clone() })
+}
+
+pub fn chain_head(log: &[ErasureRecord]) -> Vec<u8> {
+    log.last().map(|x| x.hash.clone()).unwrap_or_else(|| vec![0; 32])
+}
+
+fn link(log: &mut Vec<ErasureRecord>, key: &[u8], user: &Principal, items: Vec<ErasureItem>, now: u64) -> ErasureRecord {
+    let mut record = ErasureRecord {
+        receipt_id: log.len() as u32 + 1,
+        subject_digest: subject_digest(key, user),
+        timestamp: now,
+        items,
+        previous_hash: chain_head(log),
+        hash: vec![],
+    };
+    record.hash = record_hash(&record);
+    log.push(record.clone());
+    record
+}
+
+pub fn append(log: &mut Vec<ErasureRecord>, key: &[u8], user: &Principal, items: Vec<ErasureItem>, now: u64) -> ErasureRecord {
+    let record = link(log, key, user, items, now);
+    ic_cdk::api::set_certified_data(&record.hash);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"canister salt";
+
+    fn subject() -> Principal {
+        Principal::from_slice(&[4; 29])
+    }
+
+    fn item(dataset_id: u32, removed_entries: u32) -> ErasureItem {
+        ErasureItem { dataset_id, removed_entries, entries_digest: vec![dataset_id as u8; 32] }
+    }
+
+    #[test]
+    fn records_chain_onto_the_previous_head() {
+        let mut log = vec![];
+        assert_eq!(chain_head(&log), vec![0; 32]);
+        let first = link(&mut log, KEY, &subject(), vec![item(1, 3)], 10);
+        let second = link(&mut log, KEY, &subject(), vec![item(2, 1)], 20);
+        assert_eq!((first.receipt_id, second.receipt_id), (1, 2));
+        assert_eq!(first.previous_hash, vec![0; 32]);
+        assert_eq!(second.previous_hash, first.hash);
+        assert_eq!(chain_head(&log), second.hash);
+    }
+
+    // A receipt holder can recompute every hash; altering an earlier record breaks the chain
+    #[test]
+    fn tampering_breaks_the_chain() {
+        let mut log = vec![];
+        link(&mut log, KEY, &subject(), vec![item(1, 3)], 10);
+        link(&mut log, KEY, &subject(), vec![item(2, 1)], 20);
+        assert!(log.iter().all(|x| record_hash(x) == x.hash));
+        log[0].items[0].removed_entries = 2;
+        assert_ne!(record_hash(&log[0]), log[0].hash);
+        log[0].hash = record_hash(&log[0]);
+        assert_ne!(log[1].previous_hash, log[0].hash);
+    }
+
+    #[test]
+    fn only_digests_are_kept() {
+        let mut log = vec![];
+        let record = link(&mut log, KEY, &subject(), vec![item(1, 3)], 10);
+        assert_eq!(record.subject_digest, subject_digest(KEY, &subject()));
+        assert_ne!(record.subject_digest, subject().as_slice().to_vec());
+        assert_ne!(record.subject_digest, Sha256::digest(subject().as_slice()).to_vec());
+        assert_ne!(record.subject_digest, subject_digest(b"another salt", &subject()));
+        let entry = DatasetEntry {
+            id: RecordKey::User(subject()),
+            producer: subject(),
+            values: vec![DatasetValue { dimension_id: 1, value: Value::Metric(7) }],
+            created_at: 1,
+            updated_at: 1,
+        };
+        let changed = DatasetEntry { values: vec![DatasetValue { dimension_id: 1, value: Value::Metric(8) }], ..entry.clone() };
+        assert_ne!(entries_digest(&[entry]), entries_digest(&[changed]));
+    }
+
+    // The public links verify without exposing who was erased or from which datasets
+    #[test]
+    fn public_links_verify_the_chain() {
+        let mut log = vec![];
+        link(&mut log, KEY, &subject(), vec![item(1, 3)], 10);
+        link(&mut log, KEY, &subject(), vec![item(2, 1)], 20);
+        let links: Vec<ErasureLink> = log.iter().map(public_link).collect();
+        assert_eq!(links[0].previous_hash, vec![0; 32]);
+        for (i, x) in links.iter().enumerate() {
+            assert_eq!(link_hash(&x.previous_hash, &x.content_digest), x.hash);
+            if i > 0 { assert_eq!(x.previous_hash, links[i - 1].hash); }
+        }
+        assert_eq!(links[1].hash, chain_head(&log));
+    }
+
+    #[test]
+    fn owners_only_see_their_dataset() {
+        let mut log = vec![];
+        let record = link(&mut log, KEY, &subject(), vec![item(1, 3), item(2, 1)], 10);
+        assert_eq!(for_dataset(&record, 2).unwrap().items, vec![item(2, 1)]);
+        assert_eq!(for_dataset(&record, 3), None);
+    }
+}
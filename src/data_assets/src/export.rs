@@ -0,0 +1,364 @@
+use crate::types::*;
+
+pub const EXPORT_CHUNK_ROWS: usize = 1_000;
+
+// Columnar layout: magic, version, column count and column headers, then one batch per chunk:
+// a row count and, per column, a validity bitmap followed by u64 values or u32 offsets + utf8 data.
+// Only the first chunk carries the header, so the chunks concatenate into one file.
+const COLUMNAR_MAGIC: &[u8; 4] = b"ICDC";
+const COLUMNAR_VERSION: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell {
+    Int(u64),
+    Text(String),
+}
+
+impl From<&Value> for Cell {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Metric(met) => Cell::Int(*met as u64),
+            Value::Attribute(att) => Cell::Text(att.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnKind {
+    Int,
+    Text,
+}
+
+pub struct Table {
+    pub columns: Vec<(String, ColumnKind)>,
+    pub rows: Vec<Vec<Option<Cell>>>,
+}
+
+fn dimension_kind(dimension: &DatasetDimension) -> ColumnKind {
+    if dimension.dimension_type.is_metric() { ColumnKind::Int } else { ColumnKind::Text }
+}
+
+fn dimension_title(dimensions: &[DatasetDimension], dimension_id: u8) -> String {
+    dimensions
+        .iter()
+        .find(|dim| dim.dimension_id == dimension_id)
+        .map(|dim| dim.title.clone())
+        .unwrap_or_else(|| format!("dimension_{}", dimension_id))
+}
+
+pub fn record_key_text(key: &RecordKey) -> String {
+    match key {
+        RecordKey::User(user) => user.to_text(),
+        RecordKey::Id(id) => id.to_string(),
+    }
+}
+
+// Dataset entries, restricted to the given dimensions, in dataset dimension order.
+pub fn entries_table(config: &DatasetConfiguration, columns: &[u8], entries: &[DatasetEntry]) -> Table {
+    let dimensions: Vec<&DatasetDimension> = config.dimensions
+        .iter()
+        .filter(|dim| columns.contains(&dim.dimension_id))
+        .collect();
+    let mut headers = vec![
+        ("id".to_string(), ColumnKind::Text),
+        ("producer".to_string(), ColumnKind::Text),
+        ("created_at".to_string(), ColumnKind::Int),
+    ];
+    headers.extend(dimensions.iter().map(|dim| (dim.title.clone(), dimension_kind(dim))));
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let mut row = vec![
+                Some(Cell::Text(record_key_text(&entry.id))),
+                Some(Cell::Text(entry.producer.to_text())),
+                Some(Cell::Int(entry.created_at)),
+            ];
+            row.extend(dimensions.iter().map(|dim| {
+                entry.values
+                    .iter()
+                    .find(|val| val.dimension_id == dim.dimension_id)
+                    .map(|val| Cell::from(&val.value))
+            }));
+            row
+        })
+        .collect();
+    Table { columns: headers, rows }
+}
+
+// Analytics groups: one column per query attribute, the group count, then one sum per metric.
+pub fn analytics_table(config: &DatasetConfiguration, query: &QueryInput, analytics: &[AnalyticsType]) -> Table {
+    let mut headers: Vec<(String, ColumnKind)> = query.attributes
+        .iter()
+        .map(|id| (dimension_title(&config.dimensions, *id), ColumnKind::Text))
+        .collect();
+    headers.push(("count".to_string(), ColumnKind::Int));
+    headers.extend(query.metrics.iter().map(|id| (dimension_title(&config.dimensions, *id), ColumnKind::Int)));
+    let rows = analytics
+        .iter()
+        .map(|group| {
+            let mut row: Vec<Option<Cell>> = query.attributes
+                .iter()
+                .map(|id| {
+                    let index = group.attribute_ids.iter().position(|x| x == id)?;
+                    group.attributes.get(index).map(|val| Cell::Text(match val {
+                        Value::Attribute(att) => att.clone(),
+                        Value::Metric(met) => met.to_string(),
+                    }))
+                })
+                .collect();
+            row.push(Some(Cell::Int(group.count as u64)));
+            row.extend(query.metrics.iter().map(|id| group.metrics.get(id).map(|sum| Cell::Int(*sum as u64))));
+            row
+        })
+        .collect();
+    Table { columns: headers, rows }
+}
+
+fn csv_escape(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn json_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn encode_csv(table: &Table, rows: &[Vec<Option<Cell>>], with_header: bool) -> Vec<u8> {
+    let mut out = String::new();
+    if with_header {
+        out.push_str(&table.columns.iter().map(|(name, _)| csv_escape(name)).collect::<Vec<String>>().join(","));
+        out.push('\n');
+    }
+    for row in rows {
+        let line = row
+            .iter()
+            .map(|cell| match cell {
+                Some(Cell::Int(x)) => x.to_string(),
+                Some(Cell::Text(x)) => csv_escape(x),
+                None => String::new(),
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out.into_bytes()
+}
+
+fn encode_json_lines(table: &Table, rows: &[Vec<Option<Cell>>]) -> Vec<u8> {
+    let mut out = String::new();
+    for row in rows {
+        let fields = table.columns
+            .iter()
+            .zip(row.iter())
+            .map(|((name, _), cell)| {
+                let value = match cell {
+                    Some(Cell::Int(x)) => x.to_string(),
+                    Some(Cell::Text(x)) => json_escape(x),
+                    None => "null".to_string(),
+                };
+                format!("{}:{}", json_escape(name), value)
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        out.push('{');
+        out.push_str(&fields);
+        out.push_str("}\n");
+    }
+    out.into_bytes()
+}
+
+fn encode_columnar(table: &Table, rows: &[Vec<Option<Cell>>], header: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    if header {
+        out.extend_from_slice(COLUMNAR_MAGIC);
+        out.push(COLUMNAR_VERSION);
+        out.extend_from_slice(&(table.columns.len() as u16).to_le_bytes());
+        for (name, kind) in table.columns.iter() {
+            out.push(match kind { ColumnKind::Int => 0, ColumnKind::Text => 1 });
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+        }
+    }
+    out.extend_from_slice(&(rows.len() as u32).to_le_bytes());
+    for (index, (_, kind)) in table.columns.iter().enumerate() {
+        let cells: Vec<Option<&Cell>> = rows.iter().map(|row| row.get(index).and_then(|cell| cell.as_ref())).collect();
+        let mut validity = vec![0u8; cells.len().div_ceil(8)];
+        for (i, cell) in cells.iter().enumerate() {
+            if cell.is_some() { validity[i / 8] |= 1 << (i % 8); }
+        }
+        out.extend_from_slice(&validity);
+        match kind {
+            ColumnKind::Int => {
+                for cell in cells.iter() {
+                    let value = match cell { Some(Cell::Int(x)) => *x, _ => 0 };
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            },
+            ColumnKind::Text => {
+                let mut offsets = vec![0u32];
+                let mut data = Vec::new();
+                for cell in cells.iter() {
+                    match cell {
+                        Some(Cell::Text(x)) => data.extend_from_slice(x.as_bytes()),
+                        Some(Cell::Int(x)) => data.extend_from_slice(x.to_string().as_bytes()),
+                        None => {},
+                    }
+                    offsets.push(data.len() as u32);
+                }
+                for offset in offsets { out.extend_from_slice(&offset.to_le_bytes()); }
+                out.extend_from_slice(&data);
+            },
+        }
+    }
+    out
+}
+
+pub fn content_type(format: &ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Csv => "text/csv; charset=utf-8",
+        ExportFormat::JsonLines => "application/x-ndjson",
+        ExportFormat::Columnar => "application/octet-stream",
+    }
+}
+
+pub fn chunk_count(table: &Table) -> u32 {
+    std::cmp::max(1, table.rows.len().div_ceil(EXPORT_CHUNK_ROWS)) as u32
+}
+
+// Keeps the rows before the chunk plus at most `allowance` more, so earlier chunks stay put
+pub fn limit_rows(table: &mut Table, chunk: u32, allowance: Option<usize>) {
+    if let Some(allowance) = allowance {
+        table.rows.truncate((chunk as usize * EXPORT_CHUNK_ROWS).saturating_add(allowance));
+    }
+}
+
+pub fn encode_chunk(table: &Table, format: ExportFormat, chunk: u32) -> Result<ExportChunk, String> {
+    let chunk_count = chunk_count(table);
+    if chunk >= chunk_count {
+        return Err(format!("Chunk {} out of range, export has {} chunk(s)", chunk, chunk_count));
+    }
+    let start = chunk as usize * EXPORT_CHUNK_ROWS;
+    let end = std::cmp::min(start + EXPORT_CHUNK_ROWS, table.rows.len());
+    let rows = &table.rows[start..end];
+    let data = match format {
+        ExportFormat::Csv => encode_csv(table, rows, chunk == 0),
+        ExportFormat::JsonLines => encode_json_lines(table, rows),
+        ExportFormat::Columnar => encode_columnar(table, rows, chunk == 0),
+    };
+    Ok(ExportChunk {
+        content_type: content_type(&format).to_string(),
+        format,
+        chunk,
+        chunk_count,
+        row_count: table.rows.len() as u32,
+        data,
+        session: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn dimension(dimension_id: u8, title: &str, dimension_type: DimensionType) -> DatasetDimension {
+        DatasetDimension { dimension_id, title: title.to_string(), dimension_type }
+    }
+
+    fn config() -> DatasetConfiguration {
+        DatasetConfiguration {
+            name: "Survey".to_string(),
+            asset_id: "asset".to_string(),
+            description: "".to_string(),
+            jupyter_notebook: None,
+            dimensions: vec![
+                dimension(1, "country", DimensionType::Freetext),
+                dimension(2, "plan", DimensionType::Freetext),
+                dimension(3, "spend", DimensionType::Numerical),
+            ],
+            is_active: true,
+            category: vec![],
+            official_templates: vec![],
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    // Grouped as fetch_analytics emits it: attribute values in record order, country before plan
+    fn group() -> AnalyticsType {
+        AnalyticsType {
+            group_key: "\"FR\"--\"pro\"".to_string(),
+            attributes: vec![Value::Attribute("FR".to_string()), Value::Attribute("pro".to_string())],
+            attribute_ids: vec![1, 2],
+            metrics: HashMap::from([(3, 120)]),
+            count: 4,
+        }
+    }
+
+    #[test]
+    fn analytics_columns_follow_the_query() {
+        let query = QueryInput { dataset_id: 1, attributes: vec![2, 1], metrics: vec![3], filters: vec![] };
+        let table = analytics_table(&config(), &query, &[group()]);
+        let headers: Vec<&str> = table.columns.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(headers, vec!["plan", "country", "count", "spend"]);
+        assert_eq!(table.rows[0], vec![
+            Some(Cell::Text("pro".to_string())),
+            Some(Cell::Text("FR".to_string())),
+            Some(Cell::Int(4)),
+            Some(Cell::Int(120)),
+        ]);
+    }
+
+    fn int_table(rows: usize) -> Table {
+        Table {
+            columns: vec![("n".to_string(), ColumnKind::Int)],
+            rows: (0..rows).map(|x| vec![Some(Cell::Int(x as u64))]).collect(),
+        }
+    }
+
+    #[test]
+    fn columnar_header_only_in_first_chunk() {
+        let table = int_table(EXPORT_CHUNK_ROWS + 3);
+        let first = encode_chunk(&table, ExportFormat::Columnar, 0).unwrap().data;
+        let second = encode_chunk(&table, ExportFormat::Columnar, 1).unwrap().data;
+        assert_eq!(&first[..4], COLUMNAR_MAGIC);
+        // magic, version, column count, then one Int column named "n"
+        let header = 4 + 1 + 2 + (1 + 2 + 1);
+        assert_eq!(u32::from_le_bytes(first[header..header + 4].try_into().unwrap()), EXPORT_CHUNK_ROWS as u32);
+        // A trailing batch: row count, one validity byte, three u64 values
+        assert_eq!(second.len(), 4 + 1 + 3 * 8);
+        assert_eq!(u32::from_le_bytes(second[..4].try_into().unwrap()), 3);
+        assert_eq!(u64::from_le_bytes(second[5..13].try_into().unwrap()), EXPORT_CHUNK_ROWS as u64);
+    }
+
+    #[test]
+    fn missing_attributes_stay_empty() {
+        let query = QueryInput { dataset_id: 1, attributes: vec![2, 1], metrics: vec![], filters: vec![] };
+        let partial = AnalyticsType {
+            attributes: vec![Value::Attribute("FR".to_string())],
+            attribute_ids: vec![1],
+            ..group()
+        };
+        let table = analytics_table(&config(), &query, &[partial]);
+        assert_eq!(table.rows[0][0], None);
+        assert_eq!(table.rows[0][1], Some(Cell::Text("FR".to_string())));
+    }
+}
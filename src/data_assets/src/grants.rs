@@ -0,0 +1,170 @@
+use crate::access::AccessGrant;
+use crate::types::*;
+use ic_cdk::export::Principal;
+
+pub fn validate(dataset: &DatasetConfiguration, request: &DirectGrantRequest, now: u64) -> Result<(), String> {
+    if request.user == Principal::anonymous() {
+        return Err("Access cannot be granted to the anonymous identity".to_string());
+    }
+    if request.columns.is_empty() {
+        return Err("A grant must list at least one column".to_string());
+    }
+    if let Some(id) = request.columns.iter().find(|id| !dataset.dimensions.iter().any(|dim| dim.dimension_id == **id)) {
+        return Err(format!("Unknown dimension {}", id));
+    }
+    if request.expire_at.map(|x| x <= now).unwrap_or(false) {
+        return Err("Grant expiry must be in the future".to_string());
+    }
+    if request.row_quota == Some(0) {
+        return Err("Row quota must be positive".to_string());
+    }
+    Ok(())
+}
+
+// Revoked, superseded, expired and exhausted grants give nothing
+pub fn is_live(grant: &DirectGrant, now: u64) -> bool {
+    grant.revoked_at.is_none()
+        && grant.superseded_at.is_none()
+        && grant.expire_at.map(|x| x > now).unwrap_or(true)
+        && grant.row_quota.map(|x| grant.rows_used < x).unwrap_or(true)
+}
+
+pub fn live(state: &StableState, dataset_id: u32, user: Principal, now: u64) -> Option<&DirectGrant> {
+    state.direct_grants
+        .get(&dataset_id)
+        .and_then(|x| x.iter().find(|x| x.user == user && is_live(x, now)))
+}
+
+// The latest grant of the user, whether or not it is still live
+fn current_mut(state: &mut StableState, dataset_id: u32, user: Principal) -> Option<&mut DirectGrant> {
+    state.direct_grants
+        .get_mut(&dataset_id)
+        .and_then(|x| x.iter_mut().find(|x| x.user == user && x.superseded_at.is_none()))
+}
+
+pub fn access_grant(grant: &DirectGrant) -> AccessGrant {
+    AccessGrant {
+        expire_at: grant.expire_at,
+        ..AccessGrant::new(grant.tier.clone(), grant.columns.clone(), grant.is_gdpr)
+    }
+}
+
+// One live grant per user and dataset; a dead one is superseded and kept for the record
+pub fn issue(state: &mut StableState, dataset_id: u32, request: DirectGrantRequest, owner: Principal, now: u64) -> Result<DirectGrant, String> {
+    if live(state, dataset_id, request.user, now).is_some() {
+        return Err("User already holds a grant on this dataset, modify it instead".to_string());
+    }
+    let grant = DirectGrant {
+        dataset_id,
+        user: request.user,
+        columns: request.columns,
+        is_gdpr: request.is_gdpr,
+        tier: request.tier,
+        expire_at: request.expire_at,
+        row_quota: request.row_quota,
+        rows_used: 0,
+        granted_by: owner,
+        granted_at: now,
+        updated_at: now,
+        revoked_at: None,
+        superseded_at: None,
+    };
+    if let Some(previous) = current_mut(state, dataset_id, grant.user) {
+        previous.superseded_at = Some(now);
+    }
+    state.direct_grants.entry(dataset_id).or_default().push(grant.clone());
+    Ok(grant)
+}
+
+// Rows already served keep counting against a changed quota
+pub fn modify(state: &mut StableState, dataset_id: u32, request: DirectGrantRequest, now: u64) -> Result<DirectGrant, String> {
+    let grant = current_mut(state, dataset_id, request.user)
+        .filter(|x| x.revoked_at.is_none())
+        .ok_or("Grant not found")?;
+    grant.columns = request.columns;
+    grant.is_gdpr = request.is_gdpr;
+    grant.tier = request.tier;
+    grant.expire_at = request.expire_at;
+    grant.row_quota = request.row_quota;
+    grant.updated_at = now;
+    Ok(grant.clone())
+}
+
+pub fn revoke(state: &mut StableState, dataset_id: u32, user: Principal, now: u64) -> Result<DirectGrant, String> {
+    let grant = current_mut(state, dataset_id, user)
+        .filter(|x| x.revoked_at.is_none())
+        .ok_or("Grant not found")?;
+    grant.revoked_at = Some(now);
+    grant.updated_at = now;
+    Ok(grant.clone())
+}
+
+// Rows the grant may still serve; None without a quota
+pub fn remaining(grant: &DirectGrant) -> Option<u64> {
+    grant.row_quota.map(|x| x.saturating_sub(grant.rows_used))
+}
+
+// Callers cap what they serve at `remaining` first. Returns true when this charge exhausted the grant.
+pub fn charge(state: &mut StableState, dataset_id: u32, user: Principal, rows: usize, now: u64) -> bool {
+    let grant = state.direct_grants
+        .get_mut(&dataset_id)
+        .and_then(|x| x.iter_mut().find(|x| x.user == user && is_live(x, now)));
+    match grant {
+        Some(grant) if grant.row_quota.is_some() => {
+            grant.rows_used = grant.rows_used.saturating_add(rows as u64);
+            !is_live(grant, now)
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> Principal {
+        Principal::from_slice(&[5; 29])
+    }
+
+    fn granted(row_quota: Option<u64>) -> StableState {
+        let mut state = StableState::default();
+        let request = DirectGrantRequest { user: user(), columns: vec![1], is_gdpr: false, tier: None, expire_at: None, row_quota };
+        issue(&mut state, 1, request, Principal::from_slice(&[1; 29]), 0).unwrap();
+        state
+    }
+
+    #[test]
+    fn charges_down_to_exhaustion() {
+        let mut state = granted(Some(10));
+        assert!(!charge(&mut state, 1, user(), 4, 0));
+        assert_eq!(live(&state, 1, user(), 0).and_then(remaining), Some(6));
+        assert!(charge(&mut state, 1, user(), 6, 0));
+        assert!(live(&state, 1, user(), 0).is_none());
+    }
+
+    #[test]
+    fn reissuing_keeps_the_history() {
+        let mut state = granted(Some(10));
+        assert!(charge(&mut state, 1, user(), 10, 0));
+        let request = DirectGrantRequest { user: user(), columns: vec![2], is_gdpr: false, tier: None, expire_at: None, row_quota: None };
+        assert!(issue(&mut state, 1, request.clone(), Principal::from_slice(&[1; 29]), 5).is_ok());
+        let history = &state.direct_grants[&1];
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].superseded_at, history[0].rows_used), (Some(5), 10));
+        assert_eq!(live(&state, 1, user(), 5).map(|x| x.columns.clone()), Some(vec![2]));
+        // Only the current grant is modified or revoked
+        assert_eq!(modify(&mut state, 1, DirectGrantRequest { columns: vec![3], ..request }, 6).unwrap().granted_at, 5);
+        assert_eq!(revoke(&mut state, 1, user(), 7).unwrap().columns, vec![3]);
+        assert_eq!(state.direct_grants[&1][0].revoked_at, None);
+        assert!(revoke(&mut state, 1, user(), 8).is_err());
+    }
+
+    #[test]
+    fn unlimited_grants_are_not_charged() {
+        let mut state = granted(None);
+        assert!(!charge(&mut state, 1, user(), 1_000, 0));
+        let grant = live(&state, 1, user(), 0).unwrap();
+        assert_eq!(grant.rows_used, 0);
+        assert_eq!(remaining(grant), None);
+    }
+}
@@ -0,0 +1,547 @@
+use crate::export::{self, json_escape, Table};
+use crate::random;
+use crate::tokens;
+use crate::types::*;
+use crate::{STATE, consumer_access, get_data_by_dataset_id, get_dataset_by_dataset_id, entry_policy, process_token_data, record_rows_returned, row_allowance, run_analytics};
+use crate::tokens::Consumer;
+use ic_cdk::api::time;
+use ic_cdk::export::Principal;
+
+pub const EXPORT_SESSION_TTL: u64 = 15 * 60 * 1_000_000_000;
+
+enum Route {
+    Datasets,
+    Schema(u32),
+    Download(u32, ExportFormat),
+    Analytics,
+    NotFound,
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => { out.push(byte); i += 2; },
+                    None => out.push(b'%'),
+                }
+            },
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn parse_url(url: &str) -> (Vec<String>, Vec<(String, String)>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let segments = path
+        .split('/')
+        .filter(|seg| !seg.is_empty())
+        .map(percent_decode)
+        .collect();
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+    (segments, params)
+}
+
+fn param(params: &[(String, String)], name: &str) -> Option<String> {
+    params.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone())
+}
+
+fn parse_format(name: &str) -> Option<ExportFormat> {
+    match name {
+        "csv" => Some(ExportFormat::Csv),
+        "jsonl" | "ndjson" => Some(ExportFormat::JsonLines),
+        "bin" | "columnar" => Some(ExportFormat::Columnar),
+        _ => None,
+    }
+}
+
+fn route(segments: &[String]) -> Route {
+    let segments: Vec<&str> = segments.iter().map(|seg| seg.as_str()).collect();
+    match segments.as_slice() {
+        ["datasets"] => Route::Datasets,
+        ["datasets", id, "schema"] => match id.parse::<u32>() {
+            Ok(id) => Route::Schema(id),
+            Err(_) => Route::NotFound,
+        },
+        ["datasets", id, file] => match (id.parse::<u32>(), file.split_once('.')) {
+            (Ok(id), Some(("download", ext))) => match parse_format(ext) {
+                Some(format) => Route::Download(id, format),
+                None => Route::NotFound,
+            },
+            _ => Route::NotFound,
+        },
+        ["analytics"] => Route::Analytics,
+        _ => Route::NotFound,
+    }
+}
+
+// The analytics token is read from `Authorization: Bearer <token>`, falling back to `?token=`.
+fn request_token(request: &HttpRequest, params: &[(String, String)]) -> Option<String> {
+    request.headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.strip_prefix("Bearer ").map(|token| token.trim().to_string()))
+        .or_else(|| param(params, "token"))
+}
+
+fn response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), content_type.to_string())],
+        body,
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
+
+fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    let body = format!("{{\"error\":{}}}", json_escape(message));
+    response(status_code, "application/json", body.into_bytes())
+}
+
+fn query_error_status(error: &QueryError) -> u16 {
+    match error {
+        QueryError::Unavailable(_) => 503,
+        QueryError::BudgetExhausted(_) => 429,
+        QueryError::Invalid(_) => 400,
+        QueryError::Denied(_) => 403,
+    }
+}
+
+fn upgrade_response() -> HttpResponse {
+    HttpResponse {
+        upgrade: Some(true),
+        ..response(200, "text/plain", vec![])
+    }
+}
+
+fn json_string_list(items: &[String]) -> String {
+    format!("[{}]", items.iter().map(|item| json_escape(item)).collect::<Vec<String>>().join(","))
+}
+
+fn datasets_json(scope: &Option<Vec<u32>>) -> String {
+    STATE.with(|map| {
+        let items = map.borrow().stable.datasets
+            .iter()
+            .filter(|(id, _)| scope.as_ref().map(|x| x.contains(id)).unwrap_or(true))
+            .map(|(id, config)| format!(
+                "{{\"id\":{},\"name\":{},\"description\":{},\"category\":{},\"is_active\":{}}}",
+                id,
+                json_escape(&config.name),
+                json_escape(&config.description),
+                json_string_list(&config.category),
+                config.is_active,
+            ))
+            .collect::<Vec<String>>();
+        format!("[{}]", items.join(","))
+    })
+}
+
+fn schema_json(dataset_id: u32, config: &DatasetConfiguration) -> String {
+    let dimensions = config.dimensions
+        .iter()
+        .map(|dim| {
+            let (kind, categories) = match &dim.dimension_type {
+                DimensionType::Binary => ("Binary", None),
+                DimensionType::Categorical(values) => ("Categorical", Some(values)),
+                DimensionType::Freetext => ("Freetext", None),
+                DimensionType::Geolocation => ("Geolocation", None),
+                DimensionType::JsonObject => ("JsonObject", None),
+                DimensionType::File => ("File", None),
+                DimensionType::Numerical => ("Numerical", None),
+                DimensionType::Timestamp => ("Timestamp", None),
+            };
+            let categories = categories.map(|values| format!(",\"categories\":{}", json_string_list(values))).unwrap_or_default();
+            format!("{{\"dimension_id\":{},\"title\":{},\"type\":{}{}}}", dim.dimension_id, json_escape(&dim.title), json_escape(kind), categories)
+        })
+        .collect::<Vec<String>>();
+    format!(
+        "{{\"id\":{},\"name\":{},\"dimensions\":[{}],\"official_templates\":{}}}",
+        dataset_id,
+        json_escape(&config.name),
+        dimensions.join(","),
+        json_string_list(&config.official_templates),
+    )
+}
+
+fn parse_ids(params: &[(String, String)], name: &str) -> Result<Vec<u8>, String> {
+    match param(params, name) {
+        Some(list) => list
+            .split(',')
+            .filter(|id| !id.is_empty())
+            .map(|id| id.trim().parse::<u8>().map_err(|_| format!("Invalid dimension id in {}: {}", name, id)))
+            .collect(),
+        None => Ok(vec![]),
+    }
+}
+
+// `?dataset_id=1&attributes=1,2&metrics=3&filters=4:Europe,5:12`
+fn parse_analytics_query(params: &[(String, String)]) -> Result<QueryInput, String> {
+    let dataset_id = param(params, "dataset_id")
+        .and_then(|id| id.parse::<u32>().ok())
+        .ok_or("Missing or invalid dataset_id")?;
+    let config = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    let filters = match param(params, "filters") {
+        Some(list) => list
+            .split(',')
+            .filter(|filter| !filter.is_empty())
+            .map(|filter| {
+                let (id, value) = filter.split_once(':').ok_or(format!("Invalid filter: {}", filter))?;
+                let id = id.trim().parse::<u8>().map_err(|_| format!("Invalid filter: {}", filter))?;
+                let is_numerical = config.dimensions
+                    .iter()
+                    .any(|dim| dim.dimension_id == id && dim.dimension_type.is_metric());
+                let value = match (is_numerical, value.parse::<u32>()) {
+                    (true, Ok(met)) => Value::Metric(met),
+                    _ => Value::Attribute(value.to_string()),
+                };
+                Ok((id, value))
+            })
+            .collect::<Result<Vec<(u8, Value)>, String>>()?,
+        None => vec![],
+    };
+    Ok(QueryInput {
+        dataset_id,
+        attributes: parse_ids(params, "attributes")?,
+        metrics: parse_ids(params, "metrics")?,
+        filters,
+    })
+}
+
+pub fn session_table(session: &ExportSession) -> Option<Table> {
+    let mut table = match &session.source {
+        ExportSource::Dataset { dataset_id, columns, entries } => {
+            let config = get_dataset_by_dataset_id(*dataset_id)?;
+            export::entries_table(&config, columns, entries)
+        },
+        ExportSource::Analytics { query, result } => {
+            let config = get_dataset_by_dataset_id(query.dataset_id)?;
+            export::analytics_table(&config, query, &result.analytics)
+        },
+    };
+    export::limit_rows(&mut table, 0, session.row_limit.map(|x| x as usize));
+    Some(table)
+}
+
+fn open_session(id: String, session: ExportSession) {
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let now = time();
+        map.export_sessions.retain(|_, x| x.expire_at > now);
+        map.export_sessions.insert(id, session);
+    })
+}
+
+pub fn session_scope(session: &ExportSession) -> (u32, TokenOperation) {
+    match &session.source {
+        ExportSource::Dataset { dataset_id, .. } => (*dataset_id, TokenOperation::Download),
+        ExportSource::Analytics { query, .. } => (query.dataset_id, TokenOperation::Analytics),
+    }
+}
+
+// Encodes the first chunk and keeps the session for the rest under a random id. The whole
+// table is charged here, in the update call, capped at what a metered grant has left.
+pub async fn start_export(mut session: ExportSession, consumer: &Consumer, access: &DatasetAccess) -> Result<ExportChunk, String> {
+    let dataset_id = session_scope(&session).0;
+    session.row_limit = row_allowance(consumer, dataset_id, access).map(|x| x as u64);
+    session.token_id = consumer.token_id;
+    let table = session_table(&session).ok_or("Dataset not found")?;
+    let mut chunk = export::encode_chunk(&table, session.format, 0)?;
+    if chunk.chunk_count > 1 {
+        let id = tokens::encode(&random::random_bytes().await?);
+        open_session(id.clone(), session);
+        chunk.session = Some(id);
+    }
+    record_rows_returned(consumer, dataset_id, access, table.rows.len());
+    Ok(chunk)
+}
+
+async fn export_response(session: ExportSession, consumer: &Consumer, access: &DatasetAccess) -> HttpResponse {
+    let chunk = match start_export(session, consumer, access).await {
+        Ok(chunk) => chunk,
+        Err(msg) => return error_response(404, &msg),
+    };
+    let mut res = response(200, &chunk.content_type, chunk.data);
+    if let Some(session_id) = chunk.session {
+        res.streaming_strategy = Some(StreamingStrategy::Callback {
+            callback: candid::Func {
+                principal: ic_cdk::id(),
+                method: "http_request_streaming_callback".to_string(),
+            },
+            token: StreamingCallbackToken { session: session_id, chunk: 1 },
+        });
+    }
+    res
+}
+
+// Catalogue and schemas are answered in the query call, so token usage is not counted for them
+pub fn handle_query(request: HttpRequest) -> HttpResponse {
+    let (segments, params) = parse_url(&request.url);
+    if request.method != "GET" {
+        return error_response(405, "Method not allowed");
+    }
+    let route = route(&segments);
+    let scope = match route {
+        Route::Datasets | Route::Schema(_) => match request_token(&request, &params) {
+            Some(token) => match STATE.with(|map| tokens::scope(&map.borrow().stable, &token, time())) {
+                Ok(scope) => scope,
+                Err(error) => return error_response(401, &error.to_string()),
+            },
+            None => return error_response(401, "Missing analytics token"),
+        },
+        _ => None,
+    };
+    match route {
+        Route::Datasets => response(200, "application/json", datasets_json(&scope).into_bytes()),
+        Route::Schema(id) if !scope.map(|x| x.contains(&id)).unwrap_or(true) => {
+            error_response(403, &format!("Token is not scoped to dataset {}", id))
+        },
+        Route::Schema(id) => match get_dataset_by_dataset_id(id) {
+            Some(config) => response(200, "application/json", schema_json(id, &config).into_bytes()),
+            None => error_response(404, "Dataset not found"),
+        },
+        // Authorization needs an inter-canister call to the NFT canister
+        Route::Download(_, _) | Route::Analytics => upgrade_response(),
+        Route::NotFound => error_response(404, "Not found"),
+    }
+}
+
+pub async fn handle_update(request: HttpRequest) -> HttpResponse {
+    let (segments, params) = parse_url(&request.url);
+    let route = route(&segments);
+    if !matches!(route, Route::Download(_, _) | Route::Analytics) {
+        return handle_query(request);
+    }
+    let token = match request_token(&request, &params) {
+        Some(token) => token,
+        None => return error_response(401, "Missing analytics token"),
+    };
+    let expire_at = time() + EXPORT_SESSION_TTL;
+    match route {
+        Route::Download(dataset_id, format) => {
+            let consumer = match process_token_data(Principal::anonymous(), Some(token), dataset_id, TokenOperation::Download) {
+                Ok(consumer) => consumer,
+                Err(msg) => return error_response(401, &msg),
+            };
+            let caller = consumer.principal;
+            let access = match consumer_access(dataset_id, &consumer).await {
+                Ok(access) => access,
+                Err(msg) => return error_response(503, &msg),
+            };
+            if access.columns.is_empty() {
+                return error_response(403, "User does not own NFT linked to this dataset.");
+            }
+            let entry_policy = match entry_policy(dataset_id, caller, &access.tiers, ConsentPurpose::Download).await {
+                Ok(entry_policy) => entry_policy,
+                Err(msg) => return error_response(503, &msg),
+            };
+            let entries = get_data_by_dataset_id(dataset_id, None, Some(access.columns.clone()), &entry_policy);
+            let session = ExportSession {
+                user: caller,
+                source: ExportSource::Dataset { dataset_id, columns: access.columns.clone(), entries },
+                format,
+                expire_at,
+                row_limit: None,
+                token_id: None,
+            };
+            export_response(session, &consumer, &access).await
+        },
+        Route::Analytics => {
+            let format = match param(&params, "format") {
+                Some(name) => match parse_format(&name) {
+                    Some(format) => format,
+                    None => return error_response(400, "Unknown format"),
+                },
+                None => ExportFormat::Csv,
+            };
+            let query = match parse_analytics_query(&params) {
+                Ok(query) => query,
+                Err(msg) => return error_response(400, &msg),
+            };
+            let consumer = match process_token_data(Principal::anonymous(), Some(token), query.dataset_id, TokenOperation::Analytics) {
+                Ok(consumer) => consumer,
+                Err(msg) => return error_response(401, &msg),
+            };
+            match run_analytics(&consumer, query.clone()).await {
+                Ok((result, access)) => {
+                    let session = ExportSession {
+                        user: consumer.principal,
+                        source: ExportSource::Analytics { query, result },
+                        format,
+                        expire_at,
+                        row_limit: None,
+                        token_id: None,
+                    };
+                    export_response(session, &consumer, &access).await
+                },
+                Err(error) => error_response(query_error_status(&error), &error.to_string()),
+            }
+        },
+        _ => error_response(404, "Not found"),
+    }
+}
+
+// Usage was recorded when the session was opened, since a query cannot keep state.
+// Revoking the token still stops a running export.
+pub fn streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let now = time();
+    let session = STATE.with(|map| {
+        let map = map.borrow();
+        map.export_sessions
+            .get(&token.session)
+            .filter(|x| x.expire_at > now && tokens::is_held(&map.stable, x.user, x.token_id, now))
+            .cloned()
+    });
+    match session {
+        Some(session) => {
+            let chunk = session_table(&session).and_then(|table| export::encode_chunk(&table, session.format, token.chunk).ok());
+            match chunk {
+                Some(chunk) => StreamingCallbackHttpResponse {
+                    body: chunk.data,
+                    token: if chunk.chunk + 1 < chunk.chunk_count {
+                        Some(StreamingCallbackToken { chunk: token.chunk + 1, ..token })
+                    } else {
+                        None
+                    },
+                },
+                None => StreamingCallbackHttpResponse { body: vec![], token: None },
+            }
+        },
+        None => ic_cdk::trap("Invalid or expired streaming token"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(items: &[&str]) -> Vec<String> {
+        items.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn seed_dataset() {
+        let config = DatasetConfiguration {
+            name: "survey".to_string(),
+            asset_id: "asset".to_string(),
+            description: String::new(),
+            jupyter_notebook: None,
+            dimensions: vec![
+                DatasetDimension { dimension_id: 1, title: "region".to_string(), dimension_type: DimensionType::Freetext },
+                DatasetDimension { dimension_id: 2, title: "age".to_string(), dimension_type: DimensionType::Numerical },
+            ],
+            is_active: true,
+            category: vec![],
+            official_templates: vec![],
+            created_at: 0,
+            updated_at: 0,
+        };
+        STATE.with(|map| map.borrow_mut().stable.datasets.insert(7, config));
+    }
+
+    fn get(url: &str) -> HttpRequest {
+        HttpRequest { method: "GET".to_string(), url: url.to_string(), headers: vec![], body: vec![] }
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("%C3%A9t%C3%A9"), "été");
+        // Malformed or truncated escapes are kept as written
+        assert_eq!(percent_decode("100%zz"), "100%zz");
+        assert_eq!(percent_decode("50%"), "50%");
+        assert_eq!(percent_decode("%4"), "%4");
+    }
+
+    #[test]
+    fn urls_split_into_segments_and_params() {
+        let (segments, params) = parse_url("/datasets//7/schema?token=a%2Bb&flag&format=csv");
+        assert_eq!(segments, owned(&["datasets", "7", "schema"]));
+        assert_eq!(params, vec![
+            ("token".to_string(), "a+b".to_string()),
+            ("flag".to_string(), String::new()),
+            ("format".to_string(), "csv".to_string()),
+        ]);
+        let (segments, params) = parse_url("/");
+        assert!(segments.is_empty() && params.is_empty());
+    }
+
+    #[test]
+    fn routes() {
+        assert!(matches!(route(&owned(&["datasets"])), Route::Datasets));
+        assert!(matches!(route(&owned(&["datasets", "7", "schema"])), Route::Schema(7)));
+        assert!(matches!(route(&owned(&["datasets", "7", "download.ndjson"])), Route::Download(7, ExportFormat::JsonLines)));
+        assert!(matches!(route(&owned(&["datasets", "7", "download.bin"])), Route::Download(7, ExportFormat::Columnar)));
+        assert!(matches!(route(&owned(&["analytics"])), Route::Analytics));
+        assert!(matches!(route(&owned(&["datasets", "x", "schema"])), Route::NotFound));
+        assert!(matches!(route(&owned(&["datasets", "7", "download.xls"])), Route::NotFound));
+        assert!(matches!(route(&owned(&["datasets", "7", "export.csv"])), Route::NotFound));
+        assert!(matches!(route(&owned(&[])), Route::NotFound));
+    }
+
+    #[test]
+    fn analytics_queries_type_their_filters() {
+        seed_dataset();
+        let (_, params) = parse_url("/analytics?dataset_id=7&attributes=1&metrics=2&filters=1:Europe,2:40");
+        let query = parse_analytics_query(&params).unwrap();
+        assert_eq!(query.dataset_id, 7);
+        assert_eq!(query.attributes, vec![1]);
+        assert_eq!(query.metrics, vec![2]);
+        assert_eq!(query.filters, vec![(1, Value::Attribute("Europe".to_string())), (2, Value::Metric(40))]);
+    }
+
+    #[test]
+    fn malformed_analytics_queries_are_rejected() {
+        seed_dataset();
+        for url in [
+            "/analytics?attributes=1",
+            "/analytics?dataset_id=8",
+            "/analytics?dataset_id=7&attributes=1,x",
+            "/analytics?dataset_id=7&filters=Europe",
+            "/analytics?dataset_id=7&filters=x:Europe",
+        ] {
+            let (_, params) = parse_url(url);
+            assert!(parse_analytics_query(&params).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn bearer_header_wins_over_the_query_token() {
+        let mut request = get("/datasets?token=query");
+        let (_, params) = parse_url(&request.url);
+        assert_eq!(request_token(&request, &params), Some("query".to_string()));
+        request.headers.push(("authorization".to_string(), "Bearer header ".to_string()));
+        assert_eq!(request_token(&request, &params), Some("header".to_string()));
+    }
+
+    #[test]
+    fn catalogue_and_schema_need_a_token() {
+        seed_dataset();
+        assert_eq!(handle_query(get("/datasets")).status_code, 401);
+        assert_eq!(handle_query(get("/datasets/7/schema")).status_code, 401);
+        assert_eq!(handle_query(get("/nowhere")).status_code, 404);
+        assert_eq!(handle_query(get("/analytics?dataset_id=7")).upgrade, Some(true));
+    }
+
+    #[test]
+    fn refused_queries_map_to_statuses() {
+        let status = |error| query_error_status(&error);
+        assert_eq!(status(QueryError::Unavailable(String::new())), 503);
+        assert_eq!(status(QueryError::BudgetExhausted(String::new())), 429);
+        assert_eq!(status(QueryError::Invalid(String::new())), 400);
+        assert_eq!(status(QueryError::Denied(String::new())), 403);
+    }
+}
@@ -1,5 +1,32 @@
+mod access;
+mod audit;
+mod cache;
+mod consent;
+mod deletion;
+mod erasure;
+mod export;
+mod grants;
+mod http;
+mod masking;
+mod migration;
+mod policy;
+mod ownership;
+mod privacy;
+mod pseudonym;
+mod random;
+mod retention;
+mod roles;
+mod rows;
+mod sampling;
+mod subject;
+mod templates;
+mod tokens;
 mod types;
+mod views;
 
+use crate::privacy::DisclosureControl;
+use crate::policy::EntryPolicy;
+use crate::tokens::Consumer;
 use crate::types::*;
 use itertools::Itertools;
 use std::collections::HashMap;
@@ -25,11 +52,43 @@ fn init() {
                 dataset_producers: HashMap::new(),
                 queries: HashMap::new(),
                 analytics_tokens: HashMap::new(),
+                token_index: HashMap::new(),
+                next_token_id: 0,
+                query_templates: HashMap::new(),
+                dataset_versions: HashMap::new(),
+                cache_limits: CacheLimits::default(),
+                admins: vec![ic_cdk::api::caller()],
+                materialized_views: HashMap::new(),
+                privacy_policies: HashMap::new(),
+                privacy_budgets: HashMap::new(),
+                audit_alerts: HashMap::new(),
+                erasure_log: vec![],
+                consents: HashMap::new(),
+                tier_purposes: HashMap::new(),
+                consent_log: HashMap::new(),
+                pseudonym_salt: vec![],
+                masking_policies: HashMap::new(),
+                row_policies: HashMap::new(),
+                retention_policies: HashMap::new(),
+                purge_log: HashMap::new(),
+                dataset_deletions: HashMap::new(),
+                dataset_roles: HashMap::new(),
+                role_history: HashMap::new(),
+                owner_quorums: HashMap::new(),
+                owner_proposals: HashMap::new(),
+                ownership_offers: HashMap::new(),
+                access_providers: HashMap::new(),
+                access_cache_ttls: HashMap::new(),
+                access_merge_rules: HashMap::new(),
+                nft_activations: HashMap::new(),
+                direct_grants: HashMap::new(),
                 next_dataset_id: 0,
                 next_query_id: 0,
             },
+            ..Default::default()
         };
     });
+    arm_housekeeping(time());
 }
 
 #[pre_upgrade]
@@ -39,16 +98,19 @@ fn pre_upgrade() {
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (stable,): (StableState,) = storage::stable_restore().unwrap();
-    STATE.with(|state| *state.borrow_mut() = State { stable });
+    let stable = migration::restore(&ic_cdk::api::stable::stable_bytes(), ic_cdk::api::caller(), time()).unwrap();
+    ic_cdk::api::set_certified_data(&erasure::chain_head(&stable.erasure_log));
+    STATE.with(|state| *state.borrow_mut() = State { stable, ..Default::default() });
+    // Upgrades clear the global timer
+    arm_housekeeping(time());
 }
 
 #[update(name = "createDataSet")]
 async fn create_data_set(request: DatasetCreateRequest) -> u32 {
     STATE.with(|map| {
         let mut map = map.borrow_mut();
-        let mut id = map.stable.next_dataset_id;
-        id += 1;
+        let id = deletion::next_dataset_id(&map.stable);
+        map.stable.next_dataset_id = id;
         // Register dataset config
         let now = time();
         let dataset_config = DatasetConfiguration {
@@ -59,6 +121,7 @@ async fn create_data_set(request: DatasetCreateRequest) -> u32 {
             dimensions: request.dataset_config.dimensions,
             is_active: true,
             category: request.category,
+            official_templates: vec![],
             created_at: now,
             updated_at: now,
         };
@@ -73,27 +136,74 @@ async fn create_data_set(request: DatasetCreateRequest) -> u32 {
         // Configure init producer
         let new_producer = ProducerState {id:caller, is_enabled:true, created_at: time()};
         map.stable.dataset_producers.insert(id, vec![new_producer]);
+        roles::log(&mut map.stable, id, caller, DatasetRole::Owner, UpdateMode::Add, caller, now);
+        roles::log(&mut map.stable, id, caller, DatasetRole::Producer, UpdateMode::Add, caller, now);
         id
     })
 }
 
 
+// Soft delete: the dataset is disabled now and purged once the grace period is over
 #[update(name = "deleteDataSet")]
-async fn delete_data_set(dataset_id: u32) -> () {
+async fn delete_data_set(dataset_id: u32) -> Result<DatasetDeletion, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can delete the dataset".to_string());
+    }
     STATE.with(|map| {
         let mut map = map.borrow_mut();
-        map.stable.datasets.remove(&dataset_id);
-        map.stable.dataset_producers.remove(&dataset_id);
-    });
+        if map.stable.dataset_deletions.contains_key(&dataset_id) {
+            return Err("Dataset is already scheduled for deletion".to_string());
+        }
+        let now = time();
+        ownership::approve(&mut map.stable, dataset_id, OwnerAction::DeleteDataset, caller, now)?;
+        let deletion = deletion::schedule(&mut map.stable, dataset_id, caller, now)?;
+        drop_export_sessions(&mut map, dataset_id);
+        touch_dataset(&mut map, dataset_id);
+        Ok(deletion)
+    })
 }
 
-#[query(name = "getProducers")]
-fn get_producers(dataset_id: u32) -> Option<Vec<ProducerState>> {
+#[update(name = "restoreDataSet")]
+fn restore_data_set(dataset_id: u32) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can restore the dataset".to_string());
+    }
     STATE.with(|map| {
-        map.borrow().stable.dataset_producers.get(&dataset_id).cloned()
+        let mut map = map.borrow_mut();
+        deletion::restore(&mut map.stable, dataset_id, time())?;
+        touch_dataset(&mut map, dataset_id);
+        Ok(())
     })
 }
 
+#[query(name = "getDatasetDeletion")]
+fn get_dataset_deletion(dataset_id: u32) -> Option<DatasetDeletion> {
+    STATE.with(|map| map.borrow().stable.dataset_deletions.get(&dataset_id).cloned())
+}
+
+fn purge_dataset(state: &mut State, dataset_id: u32, now: u64) {
+    drop_export_sessions(state, dataset_id);
+    state.query_cache.invalidate_dataset(dataset_id);
+    deletion::cascade(&mut state.stable, dataset_id, now);
+}
+
+fn is_dataset_active(dataset_id: u32) -> bool {
+    STATE.with(|map| map.borrow().stable.datasets.get(&dataset_id).map(|x| x.is_active).unwrap_or(false))
+}
+
+#[query(name = "getProducers")]
+fn get_producers(dataset_id: u32) -> Result<Vec<ProducerState>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can list producers".to_string());
+    }
+    Ok(STATE.with(|map| {
+        map.borrow().stable.dataset_producers.get(&dataset_id).cloned().unwrap_or_default()
+    }))
+}
+
 #[query(name = "getDatasetsWhereUserIsProducers")]
 fn get_datasets_where_user_is_producers() -> Vec<u32> {
     STATE.with(|map| {
@@ -128,34 +238,153 @@ fn my_user() -> Principal {
 }
 
 #[update(name = "updateProducerList")]
-async fn update_producer_list(dataset_id : u32, user: Principal, mode: UpdateMode) -> () {
-    // assert user is owner
+async fn update_producer_list(dataset_id : u32, user: Principal, mode: UpdateMode) -> Result<(), String> {
+    update_dataset_role(dataset_id, user, DatasetRole::Producer, mode)
+}
+
+// Roles
+#[update(name = "updateDatasetRole")]
+fn update_dataset_role(dataset_id: u32, user: Principal, role: DatasetRole, mode: UpdateMode) -> Result<(), String> {
     let caller = ic_cdk::api::caller();
-    let test = STATE.with(|map| {
-        match map.borrow().stable.dataset_owners.get(&caller) {
-            Some(owners) => owners.contains(&dataset_id),
-            None => false,
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        if !map.stable.datasets.contains_key(&dataset_id) {
+            return Err("Dataset not found".to_string());
         }
-    });
-    assert!(test);
+        if !roles::can_assign(&roles::roles_of(&map.stable, caller, dataset_id), role, &mode, user == caller) {
+            return Err(format!("Not allowed to change the {:?} role on this dataset", role));
+        }
+        let now = time();
+        // Owners may leave on their own; adding or removing another one is up to the quorum
+        if role == DatasetRole::Owner && user != caller {
+            let action = match mode {
+                UpdateMode::Add => OwnerAction::AddOwner(user),
+                UpdateMode::Remove => OwnerAction::RemoveOwner(user),
+            };
+            ownership::approve(&mut map.stable, dataset_id, action, caller, now)?;
+        }
+        roles::update(&mut map.stable, dataset_id, user, role, mode, caller, now)
+    })
+}
+
+#[query(name = "getDatasetRoles")]
+fn get_dataset_roles(dataset_id: u32) -> Result<Vec<(Principal, Vec<DatasetRole>)>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can list roles".to_string());
+    }
+    Ok(STATE.with(|map| roles::members(&map.borrow().stable, dataset_id)))
+}
+
+#[query(name = "getMyDatasetRoles")]
+fn get_my_dataset_roles(dataset_id: u32) -> Vec<DatasetRole> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| roles::roles_of(&map.borrow().stable, caller, dataset_id))
+}
+
+#[query(name = "getRoleHistory")]
+fn get_role_history(dataset_id: u32) -> Result<Vec<RoleChange>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can review role changes".to_string());
+    }
+    Ok(STATE.with(|map| map.borrow().stable.role_history.get(&dataset_id).cloned().unwrap_or_default()))
+}
 
-    let new_entry = vec![ ProducerState {id: user, is_enabled:true, created_at: time()}];
+// Co-ownership
+#[update(name = "setOwnerQuorum")]
+fn set_owner_quorum(dataset_id: u32, quorum: u32) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only dataset owners can change the quorum".to_string());
+    }
     STATE.with(|map| {
         let mut map = map.borrow_mut();
-        match map.stable.dataset_producers.get_mut(&dataset_id) {
-            Some(producers) => {
-                if mode == UpdateMode::Add {
-                    producers.extend(new_entry);
-                }
-                else {
-                    producers.retain(|x| x.id != user);
-                }
-            },
-            None => {
-                if mode == UpdateMode::Add {
-                    map.stable.dataset_producers.insert(dataset_id, new_entry);
-                }
-            }
+        ownership::validate_quorum(&map.stable, dataset_id, quorum)?;
+        ownership::approve(&mut map.stable, dataset_id, OwnerAction::SetQuorum(quorum), caller, time())?;
+        map.stable.owner_quorums.insert(dataset_id, quorum);
+        Ok(())
+    })
+}
+
+#[query(name = "getOwnerProposals")]
+fn get_owner_proposals(dataset_id: u32) -> Result<(u32, Vec<OwnerProposal>), String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can review proposals".to_string());
+    }
+    STATE.with(|map| {
+        let map = map.borrow();
+        let now = time();
+        let proposals = map.stable.owner_proposals
+            .get(&dataset_id)
+            .map(|x| x.iter().filter(|proposal| proposal.expires_at > now).cloned().collect())
+            .unwrap_or_default();
+        Ok((ownership::quorum(&map.stable, dataset_id) as u32, proposals))
+    })
+}
+
+#[update(name = "offerDatasetOwnership")]
+fn offer_dataset_ownership(dataset_id: u32, to: Principal) -> Result<OwnershipOffer, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only dataset owners can offer ownership".to_string());
+    }
+    if to == Principal::anonymous() {
+        return Err("Ownership cannot be offered to the anonymous identity".to_string());
+    }
+    STATE.with(|map| ownership::offer(&mut map.borrow_mut().stable, dataset_id, caller, to, time()))
+}
+
+#[update(name = "acceptDatasetOwnership")]
+fn accept_dataset_ownership(dataset_id: u32, from: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| ownership::accept(&mut map.borrow_mut().stable, dataset_id, from, caller, time()))
+}
+
+// Withdrawn by the offering owner or declined by the recipient
+#[update(name = "cancelOwnershipOffer")]
+fn cancel_ownership_offer(dataset_id: u32, from: Principal, to: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if caller != from && caller != to {
+        return Err("Only the parties of an offer can cancel it".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let offers = map.stable.ownership_offers.entry(dataset_id).or_default();
+        let count = offers.len();
+        offers.retain(|x| !(x.from == from && x.to == to));
+        if offers.len() == count { Err("No pending ownership offer".to_string()) } else { Ok(()) }
+    })
+}
+
+#[query(name = "getOwnershipOffers")]
+fn get_ownership_offers() -> Vec<OwnershipOffer> {
+    let caller = ic_cdk::api::caller();
+    let now = time();
+    STATE.with(|map| {
+        map.borrow().stable.ownership_offers
+            .values()
+            .flatten()
+            .filter(|x| (x.from == caller || x.to == caller) && x.expires_at > now)
+            .cloned()
+            .collect()
+    })
+}
+
+fn has_role(user: Principal, dataset_id: u32, roles: &[DatasetRole]) -> bool {
+    STATE.with(|map| roles::has_any(&map.borrow().stable, user, dataset_id, roles))
+}
+
+fn is_admin(user: Principal) -> bool {
+    STATE.with(|map| map.borrow().stable.admins.contains(&user))
+}
+
+fn is_dataset_owner(user: Principal, dataset_id: u32) -> bool {
+    STATE.with(|map| {
+        match map.borrow().stable.dataset_owners.get(&user) {
+            Some(owners) => owners.contains(&dataset_id),
+            None => false,
         }
     })
 }
@@ -229,12 +458,29 @@ fn get_dataset_entry_counts(dataset_ids : Vec<u32>) -> Vec<(u32, usize)> {
     dataset_ids
         .iter()
         .map(|id| {
-            let nb_values: usize = STATE.with(|map| map.borrow().stable.dataset_values.get(&id).unwrap().iter().len());
+            let nb_values: usize = STATE.with(|map| map.borrow().stable.dataset_values.get(id).map(|x| x.len()).unwrap_or(0));
             (*id, nb_values)
         })
         .collect()
 }
 
+// Bumps the dataset version so cached results computed on older data are never served
+fn touch_dataset(state: &mut State, dataset_id: u32) {
+    *state.stable.dataset_versions.entry(dataset_id).or_insert(0) += 1;
+    state.query_cache.invalidate_dataset(dataset_id);
+}
+
+fn refresh_views(state: &mut State, dataset_id: u32) {
+    let now = time();
+    let stable = &mut state.stable;
+    let entries = stable.dataset_values.get(&dataset_id).cloned().unwrap_or_default();
+    // Views only aggregate records usable for plain analytics
+    let entries = consent::filter_entries(stable, dataset_id, &entries, &[ConsentPurpose::Analytics]);
+    if let Some(dataset_views) = stable.materialized_views.get_mut(&dataset_id) {
+        for view in dataset_views.iter_mut() { views::rebuild(view, &entries, now); }
+    }
+}
+
 fn put_entry(caller: Principal, dataset_id: u32, dataset_value: &DatasetEntryInput, mode: UpdateMode) -> () {
     let now = time();
     let entry = DatasetEntry {
@@ -248,60 +494,167 @@ fn put_entry(caller: Principal, dataset_id: u32, dataset_value: &DatasetEntryInp
         let mut map = map.borrow_mut();
         match map.stable.dataset_values.get_mut(&dataset_id) {
             Some(values) => {
-                let mut entries = vec![entry];
+                let mut entries = vec![entry.clone()];
                 if mode == UpdateMode::Add { entries.extend(values.clone()); }
                 map.stable.dataset_values.insert(dataset_id, entries);
             },
             None => {
-                if mode == UpdateMode::Add { map.stable.dataset_values.insert(dataset_id, vec![entry]); }
+                if mode == UpdateMode::Add { map.stable.dataset_values.insert(dataset_id, vec![entry.clone()]); }
                 else {}
             }
         }
+        if mode == UpdateMode::Add {
+            let consented = consent::is_consented(map.stable.consents.get(&dataset_id), &entry, &[ConsentPurpose::Analytics]);
+            if let Some(dataset_views) = map.stable.materialized_views.get_mut(&dataset_id).filter(|_| consented) {
+                for view in dataset_views.iter_mut() { views::apply(view, &entry, &UpdateMode::Add, now); }
+            }
+        } else {
+            refresh_views(&mut map, dataset_id);
+        }
+        touch_dataset(&mut map, dataset_id);
     })
 }
 
 #[update(name = "putManyEntries")]
-fn put_many_entries(dataset_id: u32, dataset_values: Vec<DatasetEntryInput>) -> () {
+fn put_many_entries(dataset_id: u32, dataset_values: Vec<DatasetEntryInput>) -> Result<(), String> {
     let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::WRITERS) {
+        return Err("Only dataset owners and enabled producers can add entries".to_string());
+    }
+    if !is_dataset_active(dataset_id) {
+        return Err("Dataset is not active".to_string());
+    }
     for value in dataset_values.iter() {
         put_entry(caller, dataset_id, value, UpdateMode::Add)
     };
+    Ok(())
 }
 
 #[update(name = "deleteUserEntry")]
-fn delete_user_entry(dataset_id: u32) -> () {
-    delete_data_entry(ic_cdk::api::caller(), dataset_id)
+async fn delete_user_entry(dataset_id: u32) -> Result<ErasureReceipt, String> {
+    erase_user_data(ic_cdk::api::caller(), vec![dataset_id]).await
+}
+
+// Removes the entries keyed by or produced by the user, keeping views, caches and exports consistent
+fn delete_data_entry(state: &mut State, caller: Principal, dataset_id: u32) -> Option<ErasureItem> {
+    let values = state.stable.dataset_values.get_mut(&dataset_id)?;
+    let (removed, kept): (Vec<DatasetEntry>, Vec<DatasetEntry>) = values
+        .drain(..)
+        .partition(|x| x.id == RecordKey::User(caller) || x.producer == caller);
+    *values = kept;
+    if removed.is_empty() {
+        return None;
+    }
+    let now = time();
+    // Only entries consented for analytics were ever added to the views
+    let viewed = consent::filter_entries(&state.stable, dataset_id, &removed, &[ConsentPurpose::Analytics]);
+    if let Some(dataset_views) = state.stable.materialized_views.get_mut(&dataset_id) {
+        for view in dataset_views.iter_mut() {
+            for entry in viewed.iter() { views::apply(view, entry, &UpdateMode::Remove, now); }
+        }
+    }
+    drop_export_sessions(state, dataset_id);
+    touch_dataset(state, dataset_id);
+    Some(ErasureItem {
+        dataset_id,
+        removed_entries: removed.len() as u32,
+        entries_digest: erasure::entries_digest(&removed),
+    })
+}
+
+fn drop_export_sessions(state: &mut State, dataset_id: u32) {
+    state.export_sessions.retain(|_, x| match &x.source {
+        ExportSource::Dataset { dataset_id: id, .. } => *id != dataset_id,
+        ExportSource::Analytics { query, .. } => query.dataset_id != dataset_id,
+    });
 }
 
-fn delete_data_entry(caller: Principal, dataset_id: u32) -> () {
+// The subject digest is keyed with the canister salt, drawn first if needed
+async fn erase_user_data(caller: Principal, dataset_ids: Vec<u32>) -> Result<ErasureReceipt, String> {
+    if caller == Principal::anonymous() {
+        return Err("Anonymous identity cannot request erasure".to_string());
+    }
+    draw_salt().await?;
     STATE.with(|map| {
         let mut map = map.borrow_mut();
-        match map.stable.dataset_values.get_mut(&dataset_id) {
-            Some(values) => {
-                let filtered_values: Vec<DatasetEntry>= values.iter().filter(|&x| x.id == RecordKey::User(caller)).cloned().collect();
-                if filtered_values.len() > values.len() { map.stable.dataset_values.insert(dataset_id, filtered_values); }
-                else {()}
-            },
-            None => ()
-        }
+        let items = dataset_ids
+            .iter()
+            .filter_map(|id| delete_data_entry(&mut map, caller, *id))
+            .collect();
+        let stable = &mut map.stable;
+        let record = erasure::append(&mut stable.erasure_log, &stable.pseudonym_salt, &caller, items, time());
+        Ok(ErasureReceipt { chain_head: record.hash.clone(), record, certificate: None })
     })
 }
 
 // GDPR - Data Protection
 #[update(name = "deleteAllEntriesOfUser")]
-fn delete_all_entries_of_user() -> () {
+async fn delete_all_entries_of_user() -> Result<ErasureReceipt, String> {
+    let caller = ic_cdk::api::caller();
+    let dataset_ids = STATE.with(|map| map.borrow().stable.dataset_values.keys().cloned().collect());
+    erase_user_data(caller, dataset_ids).await
+}
+
+// Certified against the chain head; only the data subject can fetch their receipts
+#[query(name = "getErasureReceipt")]
+fn get_erasure_receipt(receipt_id: u32) -> Option<ErasureReceipt> {
     STATE.with(|map| {
-        let caller = ic_cdk::api::caller();
-        for id in map.borrow().stable.datasets.keys() {
-            delete_data_entry(caller, *id)
-        }
+        let map = map.borrow();
+        let subject = erasure::subject_digest(&map.stable.pseudonym_salt, &ic_cdk::api::caller());
+        let log = &map.stable.erasure_log;
+        log.iter()
+            .find(|x| x.receipt_id == receipt_id && x.subject_digest == subject)
+            .map(|record| ErasureReceipt {
+                record: record.clone(),
+                chain_head: erasure::chain_head(log),
+                certificate: ic_cdk::api::data_certificate(),
+            })
     })
 }
 
+// Right of access: everything held about the caller, paginated
+#[query(name = "getMyData")]
+fn get_my_data(page: u32) -> DataSubjectExport {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| subject::export(&map.borrow().stable, caller, page, time()))
+}
+
+// Public chain links only; full records go to the subject and the owners of the datasets involved
+#[query(name = "getErasureLog")]
+fn get_erasure_log(offset: u32, limit: u32) -> Vec<ErasureLink> {
+    STATE.with(|map| {
+        map.borrow().stable.erasure_log
+            .iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(erasure::public_link)
+            .collect()
+    })
+}
+
+#[query(name = "getDatasetErasures")]
+fn get_dataset_erasures(dataset_id: u32) -> Result<Vec<ErasureRecord>, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can review erasures".to_string());
+    }
+    Ok(STATE.with(|map| {
+        map.borrow().stable.erasure_log
+            .iter()
+            .filter_map(|x| erasure::for_dataset(x, dataset_id))
+            .collect()
+    }))
+}
+
 // Analytical functions
-fn get_data_by_dataset_id(dataset_id : u32, sample: Option<u32>, attributes: Option<Vec<u8>>) -> Vec<DatasetEntry> {
+fn get_data_by_dataset_id(
+    dataset_id : u32,
+    sample: Option<u32>,
+    attributes: Option<Vec<u8>>,
+    entry_policy: &EntryPolicy,
+) -> Vec<DatasetEntry> {
     STATE.with(|map| {
-        let mut values = map.borrow().stable.dataset_values.get(&dataset_id).cloned().unwrap_or(vec![]);
+        let mut values = entry_policy.released_entries(&map.borrow().stable, dataset_id);
         values = match attributes {
             Some(att) => values
                 .iter()
@@ -323,8 +676,11 @@ fn get_data_by_dataset_id(dataset_id : u32, sample: Option<u32>, attributes: Opt
 }
 
 #[query(name = "getProducersStats")]
-fn get_producers_stats(dataset_id : u32) -> Vec<(Principal, u32)> {
-    STATE.with(|map| {
+fn get_producers_stats(dataset_id : u32) -> Result<Vec<(Principal, u32)>, String> {
+    if !has_role(ic_cdk::api::caller(), dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can see producer statistics".to_string());
+    }
+    Ok(STATE.with(|map| {
         match map.borrow().stable.dataset_values.get(&dataset_id) {
             Some(values) => {
                 values
@@ -340,14 +696,17 @@ fn get_producers_stats(dataset_id : u32) -> Vec<(Principal, u32)> {
                 },
             None => vec![]
         }
-    })
+    }))
 }
 
 #[query(name = "getDatasetActivity")]
 fn get_dataset_activity(
     dataset_id : u32,
-) -> Option<Vec<DateMetrics>> {
-    STATE.with(|map| {
+) -> Result<Option<Vec<DateMetrics>>, String> {
+    if !has_role(ic_cdk::api::caller(), dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can see dataset activity".to_string());
+    }
+    Ok(STATE.with(|map| {
         match map.borrow().stable.dataset_values.get(&dataset_id) {
             Some(entries) => {
                 let res = entries
@@ -360,14 +719,17 @@ fn get_dataset_activity(
             },
             None => {None}
         }
-    })
+    }))
 }
 
 #[query(name = "getDatasetQueryActivity")]
 fn get_dataset_query_activity(
     dataset_id : u32,
-) -> Vec<DateMetrics> {
-    STATE.with(|map| {
+) -> Result<Vec<DateMetrics>, String> {
+    if !has_role(ic_cdk::api::caller(), dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can see query activity".to_string());
+    }
+    Ok(STATE.with(|map| {
         map.borrow().stable.queries
             .iter()
             .filter(|(_, query)| query.query_meta.dataset_id == dataset_id)
@@ -375,87 +737,276 @@ fn get_dataset_query_activity(
             .into_iter()
             .map(|(k, v)| DateMetrics {date: k, value: v.count() as u32})
             .collect::<Vec<DateMetrics>>()
-    })
+    }))
 }
 
 async fn get_dataset_athorized_columns(dataset_id : u32, caller: Principal) -> (Vec<u8>, bool) {
-    let canister_id: &str = option_env!("CANISTER_ID_fractional_NFT").expect("Could not decode the principal of NFT canister.");
-    let access_request: CallResult<(Vec<NftMetadata>,)> = ic_cdk::call(
-            Principal::from_text(canister_id).expect("Could not decode the principal."),
-            "getUserDatasetAccess",
-            (dataset_id, caller)
-        ).await;
-    
-    match access_request {
-        Err((_, _)) => (vec![], false),
-        Ok((result,)) => {
-            if result.len()>=1 {
-                (result
-                    .iter()
-                    .map(|x| x.dimensionRestrictList.clone())
-                    .flatten()
-                    .unique()
-                    .collect::<Vec<u8>>()
-                , result[0].isGdrpEnabled)
-            } else {
-                (vec![], false)
-            }
-        },
+    let access = get_dataset_access(dataset_id, caller).await.unwrap_or_default();
+    (access.columns, access.is_gdpr)
+}
+
+// Token scopes can only narrow what the principal itself is granted
+async fn consumer_access(dataset_id : u32, consumer: &Consumer) -> Result<DatasetAccess, String> {
+    Ok(consumer.narrow(get_dataset_access(dataset_id, consumer.principal).await?))
+}
+
+async fn get_dataset_access(dataset_id : u32, caller: Principal) -> Result<DatasetAccess, String> {
+    // Datasets pending deletion grant nothing
+    if !is_dataset_active(dataset_id) {
+        return Ok(DatasetAccess::default());
+    }
+    // Owners and directly granted analysts see every column, under the dataset privacy policy
+    if has_role(caller, dataset_id, roles::ANALYSTS) {
+        return Ok(DatasetAccess {
+            columns: get_dataset_by_dataset_id(dataset_id)
+                .map(|x| x.dimensions.iter().map(|dim| dim.dimension_id).collect())
+                .unwrap_or_default(),
+            is_gdpr: true,
+            tiers: vec![],
+            expire_at: None,
+            metered: false,
+        });
+    }
+    let now = time();
+    if let Some(cached) = STATE.with(|map| map.borrow().access_cache.get(dataset_id, caller, now)) {
+        return Ok(cached);
     }
+    // Datasets without a provider only give access through direct grants
+    let config = STATE.with(|map| map.borrow().stable.access_providers.get(&dataset_id).cloned())
+        .or_else(access::default_config);
+    // Provider failures are not cached, so the next call asks again
+    let grants = match config {
+        Some(config) => access::grants(&config, dataset_id, caller).await?,
+        None => vec![],
+    };
+    Ok(STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let mut grants = access::activate(&mut map.stable, dataset_id, caller, grants, now);
+        let direct = grants::live(&map.stable, dataset_id, caller, now);
+        // A holder of an NFT is served on the NFT; the grant's quota is only spent when it is the sole source
+        let metered = grants.is_empty() && direct.map(|x| x.row_quota.is_some()).unwrap_or(false);
+        grants.extend(direct.map(grants::access_grant));
+        let rule = map.stable.access_merge_rules.get(&dataset_id).cloned().unwrap_or_default();
+        let access = DatasetAccess { metered, ..access::merge(&grants, rule) };
+        let ttl = access::cache_ttl(&map.stable, dataset_id);
+        // Denials are not cached, so a fresh purchase is seen on the next call
+        if ttl > 0 && !access.columns.is_empty() {
+            let expire_at = access.expire_at.map_or(now + ttl, |x| x.min(now + ttl));
+            map.access_cache.insert(dataset_id, caller, access.clone(), expire_at, now);
+        }
+        access
+    }))
 }
 
 #[update(name = "getAnalytics")]
 async fn get_analytics(query: QueryInput, token_data : Option<String>) -> Result<AnalyticsSuperType, String> {
-    // Check NFT ownership
     let ic_caller = ic_cdk::api::caller();
-    let caller = process_token_data(ic_caller, token_data);
+    let caller = process_token_data(ic_caller, token_data, query.dataset_id, TokenOperation::Analytics);
     match caller {
         Ok(_caller) => {
-            let (authorized, is_gdpr_enabled) = get_dataset_athorized_columns(query.dataset_id, _caller.clone()).await;
-            if authorized.len()>=1 {
-                let mut requested_fields = query.attributes.clone();
-                requested_fields.extend(query.metrics.clone());
-                let unauthorized_attributes = requested_fields
-                    .iter()
-                    .filter(|x| !authorized.contains(x))
-                    .cloned()
-                    .collect::<Vec<u8>>();
-        
-                if unauthorized_attributes.len()==0 {
-                    STATE.with(|map| {
-                        let mut id = map.borrow_mut().stable.next_query_id;
-                        id += 1;
-                        let query_state = QueryState::Pending;
-                        let now = time();
-                        let final_query = Query {
-                            timestamp: now,
-                            user: _caller,
-                            query_meta: query.clone(),
-                            query_state: query_state.clone(),
-                            is_gdpr: is_gdpr_enabled.clone(),
-                            gdpr_limit: 5,
-                        };
-                        map.borrow_mut().stable.queries.entry(id.clone()).or_insert(final_query);
-                        Ok(fetch_analytics(
-                            query.dataset_id,
-                            query.attributes,
-                            query.metrics,
-                            query.filters,
-                            is_gdpr_enabled,
-                            5,
-                        ))
-                    })
-                } else {
-                    return Err("User does not have access to following attributes".to_string());
-                }
-            } else  {
-                Err("User does not own NFT linked to this dataset.".to_string())
+            let dataset_id = query.dataset_id;
+            let (mut result, access) = run_analytics(&_caller, query).await?;
+            if let Some(allowance) = row_allowance(&_caller, dataset_id, &access) {
+                result.analytics.truncate(allowance);
             }
+            record_rows_returned(&_caller, dataset_id, &access, result.analytics.len());
+            Ok(result)
         },
         Err(_msg) => Err(_msg.to_string()),
     }
 }
 
+// Also returns the access it ran under, which decides whether the rows are charged to a grant
+async fn run_analytics(consumer: &Consumer, query: QueryInput) -> Result<(AnalyticsSuperType, DatasetAccess), QueryError> {
+    let caller = consumer.principal;
+    // Check NFT ownership
+    let access = consumer_access(query.dataset_id, consumer).await.map_err(QueryError::Unavailable)?;
+    let authorized = access.columns.clone();
+    if authorized.is_empty() {
+        return Err(QueryError::Denied("User does not own NFT linked to this dataset.".to_string()));
+    }
+    let mut requested_fields = query.attributes.clone();
+    requested_fields.extend(query.metrics.clone());
+    let unauthorized_attributes = requested_fields
+        .iter()
+        .filter(|x| !authorized.contains(x))
+        .cloned()
+        .collect::<Vec<u8>>();
+    if !unauthorized_attributes.is_empty() {
+        return Err(QueryError::Denied("User does not have access to following attributes".to_string()));
+    }
+
+    let policy = get_privacy_policy(query.dataset_id).unwrap_or_default();
+    let control = DisclosureControl::new(&policy, &access);
+    let entry_policy = entry_policy(query.dataset_id, caller, &access.tiers, ConsentPurpose::Analytics).await
+        .map_err(QueryError::Unavailable)?;
+    let result = match policy.differential_privacy {
+        Some(dp) => {
+            privacy::check_dp_query(&dp, &query).map_err(QueryError::Invalid)?;
+            reserve_privacy_budget(caller, query.dataset_id, &dp, UpdateMode::Add).map_err(QueryError::BudgetExhausted)?;
+            let seed = match random::random_seed().await {
+                Ok(seed) => seed,
+                Err(msg) => {
+                    reserve_privacy_budget(caller, query.dataset_id, &dp, UpdateMode::Remove).map_err(QueryError::Unavailable)?;
+                    return Err(QueryError::Unavailable(msg));
+                },
+            };
+            record_query(caller, &query, &control, QueryState::Accepted);
+            // Thresholds are applied on the noisy counts, never on the exact ones. l-diversity needs the
+            // exact sensitive values, so it does not apply in this mode.
+            let exact_control = DisclosureControl::default();
+            let exact = fetch_analytics(
+                query.dataset_id,
+                query.attributes.clone(),
+                query.metrics.clone(),
+                query.filters.clone(),
+                &exact_control,
+                &dp.bounds,
+                &entry_policy,
+            );
+            privacy::add_noise(exact, &query, &dp, &control, seed)
+        },
+        None => {
+            audit_and_record_query(caller, &query, &control, &policy.query_auditing, &entry_policy).map_err(QueryError::Denied)?;
+            cached_analytics(&query, &authorized, &control, &entry_policy)
+        },
+    };
+    Ok((result, access))
+}
+
+fn record_query(caller: Principal, query: &QueryInput, control: &DisclosureControl, query_state: QueryState) -> u32 {
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let mut id = map.stable.next_query_id;
+        id += 1;
+        map.stable.next_query_id = id;
+        let final_query = Query {
+            timestamp: time(),
+            user: caller,
+            query_meta: query.clone(),
+            query_state,
+            is_gdpr: control.is_enabled(),
+            gdpr_limit: control.k_threshold.unwrap_or(0),
+        };
+        map.stable.queries.entry(id).or_insert(final_query);
+        id
+    })
+}
+
+// Compares the query with the consumer's accepted history on the same dataset, then logs it.
+// Flagged queries are still served; refused ones are logged as rejected.
+fn audit_and_record_query(caller: Principal, query: &QueryInput, control: &DisclosureControl, mode: &AuditMode, entry_policy: &EntryPolicy) -> Result<(), String> {
+    let k = match (mode, control.k_threshold) {
+        (AuditMode::Off, _) | (_, None) => {
+            record_query(caller, query, control, QueryState::Accepted);
+            return Ok(());
+        },
+        (_, Some(k)) => k,
+    };
+    let finding = STATE.with(|map| {
+        let map = map.borrow();
+        let history: Vec<(u32, QueryInput)> = map.stable.queries
+            .iter()
+            .filter(|(_, x)| x.user == caller && x.query_meta.dataset_id == query.dataset_id && x.query_state == QueryState::Accepted)
+            .sorted_by(|x, y| y.0.cmp(x.0))
+            .take(audit::AUDIT_WINDOW)
+            .map(|(id, x)| (*id, x.query_meta.clone()))
+            .collect();
+        let entries = entry_policy.visible_entries(&map.stable, query.dataset_id);
+        audit::check(&entries, query, &history, k)
+    });
+    let (reason, overlapping_query) = match finding {
+        Some(finding) => finding,
+        None => {
+            record_query(caller, query, control, QueryState::Accepted);
+            return Ok(());
+        },
+    };
+    let refused = *mode == AuditMode::Refuse;
+    let query_state = if refused { QueryState::Rejected(reason.clone()) } else { QueryState::Accepted };
+    let query_id = record_query(caller, query, control, query_state);
+    STATE.with(|map| {
+        let alert = AuditAlert { query_id, user: caller, timestamp: time(), reason: reason.clone(), overlapping_query, refused };
+        map.borrow_mut().stable.audit_alerts.entry(query.dataset_id).or_default().push(alert);
+    });
+    if refused { Err(format!("Query refused by auditing: {}", reason)) } else { Ok(()) }
+}
+
+fn cached_analytics(query: &QueryInput, authorized: &[u8], control: &DisclosureControl, entry_policy: &EntryPolicy) -> AnalyticsSuperType {
+    let (key, cached) = STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let version = map.stable.dataset_versions.get(&query.dataset_id).cloned().unwrap_or(0);
+        let key = cache::cache_key(query, authorized, control, entry_policy, version);
+        let cached = map.query_cache.get(&key);
+        (key, cached)
+    });
+    if let Some(result) = cached {
+        return result;
+    }
+    // Served from the smallest materialized view able to answer, if any; views hold all unmasked,
+    // analytics-consented records only
+    let unmasked = !query.attributes.iter().chain(query.metrics.iter()).chain(query.filters.iter().map(|x| &x.0)).any(|id| entry_policy.masking.is_masked(*id));
+    let from_view = STATE.with(|map| {
+        map.borrow().stable.materialized_views
+            .get(&query.dataset_id)
+            .filter(|_| unmasked && entry_policy.rows.is_empty() && entry_policy.purposes == [ConsentPurpose::Analytics])
+            .and_then(|x| x.iter().filter(|view| views::can_answer(view, query, control)).min_by_key(|view| view.cells.len()))
+            .map(|view| views::answer(view, query, control))
+    });
+    let result = match from_view {
+        Some(result) => result,
+        None => fetch_analytics(
+            query.dataset_id,
+            query.attributes.clone(),
+            query.metrics.clone(),
+            query.filters.clone(),
+            control,
+            &[],
+            entry_policy,
+        ),
+    };
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let limits = map.stable.cache_limits.clone();
+        map.query_cache.insert(key, query.dataset_id, result.clone(), &limits);
+    });
+    result
+}
+
+// The salt keying pseudonyms, hashed columns and erasure subjects is drawn on first use and never leaves the canister
+async fn draw_salt() -> Result<(), String> {
+    if STATE.with(|map| map.borrow().stable.pseudonym_salt.is_empty()) {
+        let salt = random::random_bytes().await?;
+        STATE.with(|map| {
+            let mut map = map.borrow_mut();
+            if map.stable.pseudonym_salt.is_empty() { map.stable.pseudonym_salt = salt; }
+        });
+    }
+    Ok(())
+}
+
+async fn entry_policy(dataset_id: u32, consumer: Principal, tiers: &[String], purpose: ConsentPurpose) -> Result<EntryPolicy, String> {
+    draw_salt().await?;
+    Ok(local_entry_policy(dataset_id, consumer, tiers, purpose))
+}
+
+// Usable from queries; principals and hashed columns are removed until a salt exists
+fn local_entry_policy(dataset_id: u32, consumer: Principal, tiers: &[String], purpose: ConsentPurpose) -> EntryPolicy {
+    STATE.with(|map| EntryPolicy::new(&map.borrow().stable, dataset_id, consumer, tiers, purpose, time()))
+}
+
+fn get_privacy_policy(dataset_id: u32) -> Option<PrivacyPolicy> {
+    STATE.with(|map| map.borrow().stable.privacy_policies.get(&dataset_id).cloned())
+}
+
+fn reserve_privacy_budget(user: Principal, dataset_id: u32, dp: &DifferentialPrivacyConfig, mode: UpdateMode) -> Result<(), String> {
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let spent = map.stable.privacy_budgets.entry(dataset_id).or_default().entry(user).or_insert(0.0);
+        privacy::charge_budget(spent, dp, mode)
+    })
+}
+
 #[update(name = "getAuthorizedColumns")]
 async fn get_authorized_columns(dataset_id : u32) -> (Vec<u8>, bool)  {
     let caller = ic_cdk::api::caller();
@@ -467,14 +1018,16 @@ fn fetch_analytics(
     attributes : Vec<u8>,
     metrics : Vec<u8>,
     filters : Vec<(u8, Value)>,
-    is_gdpr : bool,
-    gdpr_limit : u32
+    control : &DisclosureControl,
+    bounds : &[MetricBound],
+    entry_policy : &EntryPolicy,
 ) -> AnalyticsSuperType {
     STATE.with(|map| {
-        match map.borrow().stable.dataset_values.get(&dataset_id) {
-            Some(values) => {
+        let map = map.borrow();
+        match map.stable.dataset_values.get(&dataset_id) {
+            Some(_) => {
                 // 1. Filter & prepare data
-                let mut base_data =  values.clone();
+                let mut base_data = entry_policy.visible_entries(&map.stable, dataset_id);
                 let op0_size: u32 = base_data.clone().len() as u32;
                 if filters.len() > 0 {
                     base_data
@@ -485,23 +1038,28 @@ fn fetch_analytics(
                 let op1_size: u32 = base_data.clone().len() as u32;
 
                 // 2. Prepare data
-                fn transform_record(att: &Vec<u8>, met: &Vec<u8>, record: &DatasetEntry) -> AnayticsPrep {
-                    let attribute_values = record.values
+                fn transform_record(att: &Vec<u8>, met: &Vec<u8>, bounds: &[MetricBound], sensitive: Option<u8>, record: &DatasetEntry) -> AnayticsPrep {
+                    let (attribute_ids, attribute_values): (Vec<u8>, Vec<Value>) = record.values
                         .iter()
-                        .filter_map(|val| if att.contains(&val.dimension_id) {Some(val.value.clone())} else {None} )
-                        .collect::<Vec<Value>>();
+                        .filter_map(|val| if att.contains(&val.dimension_id) {Some((val.dimension_id, val.value.clone()))} else {None} )
+                        .unzip();
 
                     let mut metrics_values = record.values.clone();
                     metrics_values.retain(|val| met.contains(&val.dimension_id));
+                    for val in metrics_values.iter_mut() {
+                        val.value = privacy::clamp(&val.value, val.dimension_id, bounds);
+                    }
                     AnayticsPrep {
                         att_hash: attribute_values.iter().join("--"),
                         att: attribute_values,
-                        met: metrics_values
+                        att_ids: attribute_ids,
+                        met: metrics_values,
+                        sensitive: sensitive.and_then(|id| record.values.iter().find(|val| val.dimension_id == id).map(|val| val.value.clone())),
                     }
                 }
                 let mut prepared_data: Vec<AnayticsPrep> = base_data
                     .iter()
-                    .map(|rec| transform_record(&attributes, &metrics, rec))
+                    .map(|rec| transform_record(&attributes, &metrics, bounds, control.l_diversity.as_ref().map(|x| x.dimension_id), rec))
                     .collect();
                 prepared_data.sort_by(|x, y| x.att_hash.cmp(&y.att_hash));
                 let op2_size: u32 = prepared_data.clone().len() as u32;
@@ -512,10 +1070,14 @@ fn fetch_analytics(
                     .group_by(|&x| x.att_hash.clone())
                     .into_iter()
                     .map(|(ids, records)| -> AnalyticsType {
+                        let mut attribute_ids = vec![];
                         let aggregates: (HashMap::<u8, u32>, Vec<Value>, usize) = records
                             .into_iter()
                             .fold((HashMap::<u8, u32>::new(), vec![], 0), |(mut acc, mut attributes, mut count), record| {
-                                if attributes.len()==0 {attributes = record.att.clone();}
+                                if attributes.len()==0 {
+                                    attributes = record.att.clone();
+                                    attribute_ids = record.att_ids.clone();
+                                }
                                 count += 1;
                                 for metric in metrics.iter() {
                                     match record.met.iter().find(|val| val.dimension_id == *metric) {
@@ -534,6 +1096,7 @@ fn fetch_analytics(
                         let res = AnalyticsType {
                             group_key: ids,
                             attributes: aggregates.1.clone(),
+                            attribute_ids,
                             metrics: aggregates.0.clone(),
                             count: aggregates.2.clone() as u32,
                         };
@@ -543,12 +1106,18 @@ fn fetch_analytics(
                 let op3_size: u32 = aggregated.clone().len() as u32;
 
                 // 4. GDPR
-                if is_gdpr && gdpr_limit>0 {
-                    aggregated
-                        .retain(|x| &x.count > &gdpr_limit);
+                if control.is_enabled() || control.l_diversity.is_some() {
+                    let diversity: HashMap<String, usize> = prepared_data
+                        .iter()
+                        .filter_map(|x| x.sensitive.as_ref().map(|val| (x.att_hash.clone(), val.to_string())))
+                        .unique()
+                        .counts_by(|x| x.0);
+                    privacy::suppress(&mut aggregated, &diversity, control);
                 }
                 let op4_size: u32 = aggregated.clone().len() as u32;
 
+                // Filtered sizes minus released counts would reveal the suppressed groups
+                let (op1_size, op2_size) = if control.is_enabled() { (0, 0) } else { (op1_size, op2_size) };
                 AnalyticsSuperType {
                     analytics: aggregated,
                     counts: (op0_size, op1_size, op2_size, op3_size, op4_size),
@@ -566,68 +1135,1024 @@ fn fetch_analytics(
 fn process_token_data(
     ic_caller : Principal,
     token : Option<String>,
-) -> Result<Principal, String> {
+    dataset_id : u32,
+    operation : TokenOperation,
+) -> Result<Consumer, String> {
     match token {
         Some(_token_data) => {
             STATE.with(|map| {
-                let map = map.borrow();
-                let token_search = map.stable.analytics_tokens
-                    .iter()
-                    .find(|(_, ref y)| y.token==_token_data)
-                    .clone();
-
-                match token_search {
-                    Some(token_record) => {
-                        // Check token validity
-                        // if entry.token==token {
-                        Ok(token_record.0.clone())
-                        // } else {
-                        //     Err("Invalid token".to_string())
-                        // }
-                    },
-                    None => Err("Token not found in backend".to_string()),
-                }
+                let mut map = map.borrow_mut();
+                let now = time();
+                let consumer = tokens::resolve(&map.stable, &_token_data, dataset_id, operation, now)?;
+                tokens::record_call(&mut map.stable, &consumer, ic_caller, dataset_id, operation, now);
+                Ok(consumer)
             })
         },
         None => {
-            if ic_caller.to_text() == "2vxsx-fae" {
+            if ic_caller == Principal::anonymous() {
                 Err("Anonymous identity without token is unauthorized".to_string())
             } else {
-                Ok(ic_caller)
+                Ok(Consumer::direct(ic_caller))
             }
         },
     }
 }
 
+// Rows a metered consumer may still receive; None when nothing caps them
+fn row_allowance(consumer: &Consumer, dataset_id: u32, access: &DatasetAccess) -> Option<usize> {
+    if !access.metered {
+        return None;
+    }
+    STATE.with(|map| {
+        grants::live(&map.borrow().stable, dataset_id, consumer.principal, time())
+            .and_then(grants::remaining)
+            .map(|x| x as usize)
+            .or(Some(0))
+    })
+}
+
+// Charges token usage, and the row quota of the direct grant when access came from it
+fn record_rows_returned(consumer: &Consumer, dataset_id: u32, access: &DatasetAccess, rows: usize) {
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        tokens::record_rows(&mut map.stable, consumer, rows);
+        if access.metered && grants::charge(&mut map.stable, dataset_id, consumer.principal, rows, time()) {
+            map.access_cache.invalidate(Some(dataset_id), Some(consumer.principal));
+        }
+    });
+}
+
 #[update(name = "getDatasetDownload")]
 async fn get_dataset_download(dataset_id : u32, token_data: Option<String>) -> Result<Vec<DatasetEntry>, String> {
     let ic_caller = ic_cdk::api::caller();
-    let caller = process_token_data(ic_caller, token_data);
+    let caller = process_token_data(ic_caller, token_data, dataset_id, TokenOperation::Download);
     match caller {
         Ok(_caller) => {
-            let (authorized, _) = get_dataset_athorized_columns(dataset_id, _caller).await;
-            Ok(get_data_by_dataset_id(dataset_id, None, Some(authorized)))
+            let access = consumer_access(dataset_id, &_caller).await?;
+            let entry_policy = entry_policy(dataset_id, _caller.principal, &access.tiers, ConsentPurpose::Download).await?;
+            let mut entries = get_data_by_dataset_id(dataset_id, None, Some(access.columns.clone()), &entry_policy);
+            if let Some(allowance) = row_allowance(&_caller, dataset_id, &access) {
+                entries.truncate(allowance);
+            }
+            record_rows_returned(&_caller, dataset_id, &access, entries.len());
+            Ok(entries)
         },
         Err(_msg) => Err(_msg.to_string()),
     }
 }
 
-#[query(name = "getDatasetSample")]
-fn get_dataset_sample(dataset_id : u32) -> Vec<DatasetEntry> {
-    get_data_by_dataset_id(dataset_id, Some(30), None)
+// A random preview of the dataset, authorized, masked and charged like any other sample. The seed
+// is fixed so repeated calls return the same rows instead of walking through the dataset.
+#[update(name = "getDatasetSample")]
+async fn get_dataset_sample(dataset_id : u32) -> Result<Vec<DatasetEntry>, String> {
+    let request = SampleRequest { dataset_id, size: 30, method: SampleMethod::Random, seed: Some(0) };
+    Ok(get_sample(request, None).await?.entries)
+}
+
+#[update(name = "getSample")]
+async fn get_sample(request: SampleRequest, token_data: Option<String>) -> Result<SampleResult, String> {
+    let ic_caller = ic_cdk::api::caller();
+    let consumer = process_token_data(ic_caller, token_data, request.dataset_id, TokenOperation::Download)?;
+    let caller = consumer.principal;
+    let access = consumer_access(request.dataset_id, &consumer).await?;
+    let authorized = access.columns.clone();
+    if authorized.is_empty() {
+        return Err("User does not own NFT linked to this dataset.".to_string());
+    }
+    if let SampleMethod::Stratified(dimension_id) = request.method {
+        if !authorized.contains(&dimension_id) {
+            return Err("User does not have access to the stratification dimension".to_string());
+        }
+    }
+    let seed = match request.seed {
+        Some(seed) => seed,
+        None => random::random_seed().await?,
+    };
+    let entry_policy = entry_policy(request.dataset_id, caller, &access.tiers, ConsentPurpose::Download).await?;
+    let entries = get_data_by_dataset_id(request.dataset_id, None, None, &entry_policy);
+    let entries = sampling::sample(&entries, &request.method, request.size, seed)
+        .into_iter()
+        .map(|entry| DatasetEntry {
+            values: entry.values.into_iter().filter(|val| authorized.contains(&val.dimension_id)).collect(),
+            ..entry
+        })
+        .take(row_allowance(&consumer, request.dataset_id, &access).unwrap_or(usize::MAX))
+        .collect::<Vec<DatasetEntry>>();
+    record_rows_returned(&consumer, request.dataset_id, &access, entries.len());
+    Ok(SampleResult { seed, entries })
 }
 
+// Analytics tokens
 #[update(name = "registerAnalyticsToken")]
-fn register_analytics_token(token: String) -> String {
+async fn register_analytics_token(request: TokenRequest) -> Result<IssuedToken, String> {
+    let caller = ic_cdk::api::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous identity cannot register tokens".to_string());
+    }
+    tokens::validate(&request)?;
+    let secret = random::random_bytes().await?;
+    STATE.with(|map| tokens::issue(&mut map.borrow_mut().stable, caller, request, &secret, time()))
+}
+
+#[query(name = "listAnalyticsTokens")]
+fn list_analytics_tokens() -> Vec<AnalyticsTokenInfo> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| {
+        map.borrow().stable.analytics_tokens
+            .get(&caller)
+            .map(|x| x.iter().map(tokens::info).collect())
+            .unwrap_or_default()
+    })
+}
+
+// Lets a holder review where a token has been used, e.g. to spot a leaked one
+#[query(name = "getAnalyticsTokenUsage")]
+fn get_analytics_token_usage(id: u32) -> Result<TokenUsage, String> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| {
+        map.borrow().stable.analytics_tokens
+            .get(&caller)
+            .and_then(|x| x.iter().find(|x| x.id == id))
+            .map(|x| x.usage.clone())
+            .ok_or_else(|| "Token not found".to_string())
+    })
+}
+
+#[update(name = "revokeAnalyticsToken")]
+fn revoke_analytics_token(id: u32) -> Result<AnalyticsTokenInfo, String> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| tokens::revoke(&mut map.borrow_mut().stable, caller, id, time()))
+}
+
+// Exports
+// Takes the released rows once and returns the first chunk; later chunks come from the export
+// session, so entries added in between cannot shift the chunk boundaries.
+#[update(name = "getDatasetExport")]
+async fn get_dataset_export(dataset_id : u32, format: ExportFormat, token_data: Option<String>) -> Result<ExportChunk, String> {
+    let ic_caller = ic_cdk::api::caller();
+    let consumer = process_token_data(ic_caller, token_data, dataset_id, TokenOperation::Download)?;
+    let caller = consumer.principal;
+    let access = consumer_access(dataset_id, &consumer).await?;
+    let authorized = access.columns.clone();
+    if authorized.is_empty() {
+        return Err("User does not own NFT linked to this dataset.".to_string());
+    }
+    let entry_policy = entry_policy(dataset_id, caller, &access.tiers, ConsentPurpose::Download).await?;
+    let entries = get_data_by_dataset_id(dataset_id, None, Some(authorized.clone()), &entry_policy);
+    let session = ExportSession {
+        user: caller,
+        source: ExportSource::Dataset { dataset_id, columns: authorized, entries },
+        format,
+        expire_at: time() + http::EXPORT_SESSION_TTL,
+        row_limit: None,
+        token_id: None,
+    };
+    http::start_export(session, &consumer, &access).await
+}
+
+// Runs the query once and returns the first chunk; the result is kept in an export session
+// so later chunks spend no privacy budget and are not logged or audited again.
+#[update(name = "getAnalyticsExport")]
+async fn get_analytics_export(query: QueryInput, format: ExportFormat, token_data: Option<String>) -> Result<ExportChunk, String> {
+    let ic_caller = ic_cdk::api::caller();
+    let consumer = process_token_data(ic_caller, token_data, query.dataset_id, TokenOperation::Analytics)?;
+    let (result, access) = run_analytics(&consumer, query.clone()).await?;
+    let session = ExportSession {
+        user: consumer.principal,
+        source: ExportSource::Analytics { query, result },
+        format,
+        expire_at: time() + http::EXPORT_SESSION_TTL,
+        row_limit: None,
+        token_id: None,
+    };
+    http::start_export(session, &consumer, &access).await
+}
+
+#[update(name = "getExportChunk")]
+fn get_export_chunk(session_id: String, chunk: u32, token_data: Option<String>) -> Result<ExportChunk, String> {
+    let ic_caller = ic_cdk::api::caller();
+    let session = STATE.with(|map| map.borrow().export_sessions.get(&session_id).cloned())
+        .filter(|x| x.expire_at > time())
+        .ok_or("Export session not found or expired")?;
+    let (dataset_id, operation) = http::session_scope(&session);
+    let consumer = process_token_data(ic_caller, token_data, dataset_id, operation)?;
+    if consumer.principal != session.user {
+        return Err("Export session belongs to another user".to_string());
+    }
+    let table = http::session_table(&session).ok_or("Dataset not found")?;
+    let encoded = export::encode_chunk(&table, session.format, chunk)?;
+    Ok(ExportChunk { session: Some(session_id), ..encoded })
+}
+
+// HTTP gateway
+#[query(name = "http_request")]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    http::handle_query(request)
+}
+
+#[update(name = "http_request_update")]
+async fn http_request_update(request: HttpRequest) -> HttpResponse {
+    http::handle_update(request).await
+}
+
+#[query(name = "http_request_streaming_callback")]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    http::streaming_callback(token)
+}
+
+// Query templates
+// Any consumer of the dataset can save templates over the columns they are authorized for
+#[update(name = "saveQueryTemplate")]
+async fn save_query_template(dataset_id: u32, input: QueryTemplateInput) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    let authorized = get_dataset_access(dataset_id, caller).await?.columns;
+    if authorized.is_empty() {
+        return Err("User does not have access to this dataset".to_string());
+    }
+    if input.attributes.iter().chain(input.metrics.iter()).any(|x| !authorized.contains(x)) {
+        return Err("User does not have access to following attributes".to_string());
+    }
+    let config = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    templates::validate(&config, &input)?;
     STATE.with(|map| {
+        let mut map = map.borrow_mut();
         let now = time();
-        let token_state = AnalyticsToken {
-            token: token.clone(),
-            lifetime: 3600,
+        let dataset_templates = map.stable.query_templates.entry(dataset_id).or_default();
+        match dataset_templates.iter_mut().find(|x| x.name == input.name) {
+            Some(template) if template.owner != caller => Err("A template with this name already exists".to_string()),
+            Some(template) => {
+                *template = QueryTemplate {
+                    description: input.description,
+                    attributes: input.attributes,
+                    metrics: input.metrics,
+                    filters: input.filters,
+                    parameters: input.parameters,
+                    updated_at: now,
+                    ..template.clone()
+                };
+                Ok(())
+            },
+            None => {
+                dataset_templates.push(QueryTemplate {
+                    name: input.name,
+                    dataset_id,
+                    owner: caller,
+                    description: input.description,
+                    attributes: input.attributes,
+                    metrics: input.metrics,
+                    filters: input.filters,
+                    parameters: input.parameters,
+                    shared_with: vec![],
+                    is_official: false,
+                    created_at: now,
+                    updated_at: now,
+                });
+                Ok(())
+            },
+        }
+    })
+}
+
+#[update(name = "deleteQueryTemplate")]
+fn delete_query_template(dataset_id: u32, name: String) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    let is_owner = is_dataset_owner(caller, dataset_id);
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let dataset_templates = map.stable.query_templates.get_mut(&dataset_id).ok_or("Template not found")?;
+        let template = dataset_templates.iter().find(|x| x.name == name).ok_or("Template not found")?;
+        if template.owner != caller && !is_owner {
+            return Err("Only the template or dataset owner can delete it".to_string());
+        }
+        dataset_templates.retain(|x| x.name != name);
+        if let Some(config) = map.stable.datasets.get_mut(&dataset_id) {
+            config.official_templates.retain(|x| x != &name);
+        }
+        Ok(())
+    })
+}
+
+#[update(name = "shareQueryTemplate")]
+fn share_query_template(dataset_id: u32, name: String, user: Principal, mode: UpdateMode) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let template = map.stable.query_templates
+            .get_mut(&dataset_id)
+            .and_then(|x| x.iter_mut().find(|x| x.name == name))
+            .ok_or("Template not found")?;
+        if template.owner != caller {
+            return Err("Only the template owner can share it".to_string());
+        }
+        template.shared_with.retain(|x| *x != user);
+        if mode == UpdateMode::Add {
+            template.shared_with.push(user);
+        }
+        Ok(())
+    })
+}
+
+#[update(name = "publishQueryTemplate")]
+fn publish_query_template(dataset_id: u32, name: String, is_official: bool) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can publish templates".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let template = map.stable.query_templates
+            .get_mut(&dataset_id)
+            .and_then(|x| x.iter_mut().find(|x| x.name == name))
+            .ok_or("Template not found")?;
+        template.is_official = is_official;
+        template.updated_at = time();
+        if let Some(config) = map.stable.datasets.get_mut(&dataset_id) {
+            config.official_templates.retain(|x| x != &name);
+            if is_official { config.official_templates.push(name); }
+        }
+        Ok(())
+    })
+}
+
+#[query(name = "getQueryTemplates")]
+fn get_query_templates(dataset_id: u32) -> Vec<QueryTemplate> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| {
+        match map.borrow().stable.query_templates.get(&dataset_id) {
+            Some(values) => values.iter().filter(|x| templates::is_visible(x, caller)).cloned().collect(),
+            None => vec![],
+        }
+    })
+}
+
+#[update(name = "runQueryTemplate")]
+async fn run_query_template(dataset_id: u32, name: String, values: Vec<(String, Value)>, token_data: Option<String>) -> Result<AnalyticsSuperType, String> {
+    let ic_caller = ic_cdk::api::caller();
+    let consumer = process_token_data(ic_caller, token_data, dataset_id, TokenOperation::Analytics)?;
+    let caller = consumer.principal;
+    let config = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    let template = STATE.with(|map| {
+        map.borrow().stable.query_templates
+            .get(&dataset_id)
+            .and_then(|x| x.iter().find(|x| x.name == name && templates::is_visible(x, caller)).cloned())
+    }).ok_or("Template not found")?;
+    let query = templates::bind(&config, &template, &values)?;
+    let (mut result, access) = run_analytics(&consumer, query).await?;
+    if let Some(allowance) = row_allowance(&consumer, dataset_id, &access) {
+        result.analytics.truncate(allowance);
+    }
+    record_rows_returned(&consumer, dataset_id, &access, result.analytics.len());
+    Ok(result)
+}
+
+// Query cache
+#[query(name = "getDatasetVersion")]
+fn get_dataset_version(dataset_id: u32) -> u64 {
+    STATE.with(|map| map.borrow().stable.dataset_versions.get(&dataset_id).cloned().unwrap_or(0))
+}
+
+#[query(name = "getQueryCacheStats")]
+fn get_query_cache_stats() -> QueryCacheStats {
+    STATE.with(|map| {
+        let map = map.borrow();
+        map.query_cache.stats(&map.stable.cache_limits)
+    })
+}
+
+#[update(name = "setQueryCacheLimits")]
+fn set_query_cache_limits(limits: CacheLimits) -> Result<(), String> {
+    if !is_admin(ic_cdk::api::caller()) {
+        return Err("Only canister admins can configure the cache".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.query_cache.evict(&limits);
+        map.stable.cache_limits = limits;
+    });
+    Ok(())
+}
+
+#[update(name = "clearQueryCache")]
+fn clear_query_cache() -> Result<(), String> {
+    if !is_admin(ic_cdk::api::caller()) {
+        return Err("Only canister admins can clear the cache".to_string());
+    }
+    STATE.with(|map| map.borrow_mut().query_cache.clear());
+    Ok(())
+}
+
+#[update(name = "updateAdminList")]
+fn update_admin_list(user: Principal, mode: UpdateMode) -> Result<(), String> {
+    if !is_admin(ic_cdk::api::caller()) {
+        return Err("Only canister admins can manage admins".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.stable.admins.retain(|x| *x != user);
+        if mode == UpdateMode::Add { map.stable.admins.push(user); }
+    });
+    Ok(())
+}
+
+// Materialized views
+#[update(name = "createMaterializedView")]
+fn create_materialized_view(dataset_id: u32, input: MaterializedViewInput) -> Result<MaterializedViewInfo, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can declare materialized views".to_string());
+    }
+    let config = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    let mut dimensions = input.attributes.clone();
+    dimensions.extend(input.metrics.iter());
+    if let Some(id) = dimensions.iter().find(|id| !config.dimensions.iter().any(|dim| dim.dimension_id == **id)) {
+        return Err(format!("Unknown dimension {}", id));
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let now = time();
+        let entries = map.stable.dataset_values.get(&dataset_id).cloned().unwrap_or_default();
+        let entries = consent::filter_entries(&map.stable, dataset_id, &entries, &[ConsentPurpose::Analytics]);
+        let dataset_views = map.stable.materialized_views.entry(dataset_id).or_default();
+        if dataset_views.iter().any(|x| x.name == input.name) {
+            return Err("A view with this name already exists".to_string());
+        }
+        let mut view = MaterializedView {
+            name: input.name,
+            dataset_id,
+            attributes: input.attributes,
+            metrics: input.metrics,
+            cells: HashMap::new(),
             created_at: now,
-            expire_at: now,
+            updated_at: now,
         };
-        map.borrow_mut().stable.analytics_tokens.insert(ic_cdk::api::caller(), token_state);
-        token
+        views::rebuild(&mut view, &entries, now);
+        let info = views::info(&view);
+        dataset_views.push(view);
+        Ok(info)
+    })
+}
+
+#[update(name = "deleteMaterializedView")]
+fn delete_materialized_view(dataset_id: u32, name: String) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can delete materialized views".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        ownership::approve(&mut map.stable, dataset_id, OwnerAction::DeleteMaterializedView(name.clone()), caller, time())?;
+        if let Some(dataset_views) = map.stable.materialized_views.get_mut(&dataset_id) {
+            dataset_views.retain(|x| x.name != name);
+        }
+        Ok(())
+    })
+}
+
+#[query(name = "getMaterializedViews")]
+fn get_materialized_views(dataset_id: u32) -> Result<Vec<MaterializedViewInfo>, String> {
+    if !has_role(ic_cdk::api::caller(), dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can list views".to_string());
+    }
+    Ok(STATE.with(|map| {
+        match map.borrow().stable.materialized_views.get(&dataset_id) {
+            Some(dataset_views) => dataset_views.iter().map(views::info).collect(),
+            None => vec![],
+        }
+    }))
+}
+
+// Differential privacy
+#[update(name = "setDifferentialPrivacy")]
+fn set_differential_privacy(dataset_id: u32, config: Option<DifferentialPrivacyConfig>) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change the privacy policy".to_string());
+    }
+    let dataset = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    if let Some(dp) = &config {
+        privacy::validate_dp_config(&dataset, dp)?;
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.stable.privacy_policies.entry(dataset_id).or_default().differential_privacy = config;
+        touch_dataset(&mut map, dataset_id);
+    });
+    Ok(())
+}
+
+#[update(name = "setPrivacyPolicy")]
+fn set_privacy_policy(dataset_id: u32, policy: PrivacyPolicy) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change the privacy policy".to_string());
+    }
+    let dataset = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    privacy::validate_policy(&dataset, &policy)?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.stable.privacy_policies.insert(dataset_id, policy);
+        touch_dataset(&mut map, dataset_id);
+    });
+    Ok(())
+}
+
+#[update(name = "setPseudonymization")]
+fn set_pseudonymization(dataset_id: u32, config: PseudonymConfig) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change the privacy policy".to_string());
+    }
+    pseudonym::validate(&config)?;
+    STATE.with(|map| {
+        map.borrow_mut().stable.privacy_policies.entry(dataset_id).or_default().pseudonymization = config;
+    });
+    Ok(())
+}
+
+// Column masking
+#[update(name = "setMaskingPolicy")]
+fn set_masking_policy(dataset_id: u32, policy: MaskingPolicy) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change the masking policy".to_string());
+    }
+    let dataset = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    masking::validate(&dataset, &policy)?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.stable.masking_policies.insert(dataset_id, policy);
+        touch_dataset(&mut map, dataset_id);
+    });
+    Ok(())
+}
+
+#[query(name = "getMaskingPolicy")]
+fn get_masking_policy(dataset_id: u32) -> MaskingPolicy {
+    STATE.with(|map| map.borrow().stable.masking_policies.get(&dataset_id).cloned().unwrap_or_default())
+}
+
+// Row-level security
+#[update(name = "setRowPolicies")]
+fn set_row_policies(dataset_id: u32, policies: Vec<RowPolicy>) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change row policies".to_string());
+    }
+    let dataset = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    rows::validate(&dataset, &policies)?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.stable.row_policies.insert(dataset_id, policies);
+        touch_dataset(&mut map, dataset_id);
+    });
+    Ok(())
+}
+
+#[query(name = "getRowPolicies")]
+fn get_row_policies(dataset_id: u32) -> Result<Vec<RowPolicy>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can review row policies".to_string());
+    }
+    Ok(STATE.with(|map| map.borrow().stable.row_policies.get(&dataset_id).cloned().unwrap_or_default()))
+}
+
+// Access providers
+#[update(name = "setAccessProvider")]
+fn set_access_provider(dataset_id: u32, config: AccessProviderConfig) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change the access provider".to_string());
+    }
+    let dataset = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    access::validate(&dataset, &config)?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.stable.access_providers.insert(dataset_id, config);
+        map.access_cache.invalidate(Some(dataset_id), None);
+    });
+    Ok(())
+}
+
+// The configured provider, or the build default when none was set
+#[query(name = "getAccessProvider")]
+fn get_access_provider(dataset_id: u32) -> Result<Option<AccessProviderConfig>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can review the access provider".to_string());
+    }
+    Ok(STATE.with(|map| map.borrow().stable.access_providers.get(&dataset_id).cloned()).or_else(access::default_config))
+}
+
+// Direct access grants
+#[update(name = "grantDatasetAccess")]
+fn grant_dataset_access(dataset_id: u32, request: DirectGrantRequest) -> Result<DirectGrant, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can grant access".to_string());
+    }
+    let dataset = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    let now = time();
+    grants::validate(&dataset, &request, now)?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let user = request.user;
+        let grant = grants::issue(&mut map.stable, dataset_id, request, caller, now)?;
+        map.access_cache.invalidate(Some(dataset_id), Some(user));
+        Ok(grant)
+    })
+}
+
+#[update(name = "updateDatasetGrant")]
+fn update_dataset_grant(dataset_id: u32, request: DirectGrantRequest) -> Result<DirectGrant, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can modify grants".to_string());
+    }
+    let dataset = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    let now = time();
+    grants::validate(&dataset, &request, now)?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let user = request.user;
+        let grant = grants::modify(&mut map.stable, dataset_id, request, now)?;
+        map.access_cache.invalidate(Some(dataset_id), Some(user));
+        Ok(grant)
+    })
+}
+
+#[update(name = "revokeDatasetGrant")]
+fn revoke_dataset_grant(dataset_id: u32, user: Principal) -> Result<DirectGrant, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can revoke grants".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let grant = grants::revoke(&mut map.stable, dataset_id, user, time())?;
+        map.access_cache.invalidate(Some(dataset_id), Some(user));
+        Ok(grant)
+    })
+}
+
+#[query(name = "getDatasetGrants")]
+fn get_dataset_grants(dataset_id: u32) -> Result<Vec<DirectGrant>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can review grants".to_string());
+    }
+    Ok(STATE.with(|map| map.borrow().stable.direct_grants.get(&dataset_id).cloned().unwrap_or_default()))
+}
+
+#[query(name = "getMyDatasetGrants")]
+fn get_my_dataset_grants() -> Vec<DirectGrant> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| {
+        map.borrow().stable.direct_grants
+            .values()
+            .flatten()
+            .filter(|x| x.user == caller)
+            .sorted_by_key(|x| (x.dataset_id, x.granted_at))
+            .cloned()
+            .collect()
     })
-}
\ No newline at end of file
+}
+
+// Cached authorization decisions
+#[update(name = "setAccessCacheTtl")]
+fn set_access_cache_ttl(dataset_id: u32, ttl_seconds: u32) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change the access cache TTL".to_string());
+    }
+    access::validate_cache_ttl(ttl_seconds)?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.stable.access_cache_ttls.insert(dataset_id, ttl_seconds);
+        map.access_cache.invalidate(Some(dataset_id), None);
+    });
+    Ok(())
+}
+
+#[update(name = "setAccessMergeRule")]
+fn set_access_merge_rule(dataset_id: u32, rule: AccessMergeRule) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change the access merge rule".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.stable.access_merge_rules.insert(dataset_id, rule);
+        map.access_cache.invalidate(Some(dataset_id), None);
+    });
+    Ok(())
+}
+
+#[query(name = "getAccessMergeRule")]
+fn get_access_merge_rule(dataset_id: u32) -> AccessMergeRule {
+    STATE.with(|map| map.borrow().stable.access_merge_rules.get(&dataset_id).cloned().unwrap_or_default())
+}
+
+#[query(name = "getMyNftActivations")]
+fn get_my_nft_activations() -> Vec<(u32, NftActivation)> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| {
+        map.borrow().stable.nft_activations
+            .iter()
+            .flat_map(|(id, activations)| activations.iter().filter(|x| x.user == caller).map(move |x| (*id, x.clone())))
+            .sorted_by_key(|(id, x)| (*id, x.activated_at))
+            .collect()
+    })
+}
+
+#[query(name = "getAccessCacheTtl")]
+fn get_access_cache_ttl(dataset_id: u32) -> u32 {
+    STATE.with(|map| map.borrow().stable.access_cache_ttls.get(&dataset_id).cloned().unwrap_or(access::DEFAULT_CACHE_TTL))
+}
+
+#[update(name = "flushAccessCache")]
+fn flush_access_cache(dataset_id: u32) -> Result<u32, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can flush the access cache".to_string());
+    }
+    Ok(STATE.with(|map| map.borrow_mut().access_cache.invalidate(Some(dataset_id), None)))
+}
+
+// Called by the NFT or sales canister when tokens transfer; returns the number of dropped decisions
+#[update(name = "notifyAccessChange")]
+fn notify_access_change(changes: Vec<AccessInvalidation>) -> Result<u32, String> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(change) = changes.iter().find(|x| !access::notifiers(&map.stable, x.dataset_id).contains(&caller)) {
+            return Err(match change.dataset_id {
+                Some(dataset_id) => format!("Only the access provider canisters can invalidate dataset {}", dataset_id),
+                None => "Only the NFT and sales canisters can invalidate every dataset".to_string(),
+            });
+        }
+        Ok(changes.iter().map(|x| map.access_cache.invalidate(x.dataset_id, x.user)).sum())
+    })
+}
+
+// What a consumer can see of a dataset and why
+#[update(name = "explainAccess")]
+async fn explain_access(dataset_id: u32, token_data: Option<String>) -> Result<AccessExplanation, String> {
+    let ic_caller = ic_cdk::api::caller();
+    let consumer = process_token_data(ic_caller, token_data, dataset_id, TokenOperation::Analytics)?;
+    let caller = consumer.principal;
+    let access = consumer_access(dataset_id, &consumer).await?;
+    let policy = get_privacy_policy(dataset_id).unwrap_or_default();
+    let control = DisclosureControl::new(&policy, &access);
+    let entry_policy = local_entry_policy(dataset_id, caller, &access.tiers, ConsentPurpose::Analytics);
+    Ok(AccessExplanation {
+        dataset_id,
+        columns: access.columns,
+        tiers: access.tiers,
+        is_gdpr: access.is_gdpr,
+        k_threshold: control.k_threshold,
+        consent_purposes: entry_policy.purposes,
+        masks: entry_policy.masking.masks,
+        row_predicates: entry_policy.rows,
+        pseudonymization: policy.pseudonymization,
+        access_expires_at: access.expire_at,
+    })
+}
+
+// Retention and deferred dataset deletion run on the global timer, at most once a minute
+const HOUSEKEEPING_INTERVAL: u64 = 60 * 1_000_000_000;
+
+fn arm_housekeeping(at: u64) {
+    // Safety: a plain system call that takes no memory
+    unsafe { ic0::global_timer_set(at as i64); }
+}
+
+// The timer is re-armed before any work, and the work runs in a call to ourselves: a trap there,
+// say on the instruction limit, only rolls back that call and the next run still comes.
+#[export_name = "canister_global_timer"]
+extern "C" fn global_timer() {
+    ic_cdk::setup();
+    arm_housekeeping(time() + HOUSEKEEPING_INTERVAL);
+    ic_cdk::spawn(async {
+        let result: CallResult<(Result<(), String>,)> = ic_cdk::call(ic_cdk::id(), "runHousekeeping", ()).await;
+        if result.is_ok() {
+            // A retention backlog is picked up again right away
+            let now = time();
+            let next_purge_at = STATE.with(|map| map.borrow().next_purge_at);
+            arm_housekeeping(next_purge_at.clamp(now, now + HOUSEKEEPING_INTERVAL));
+        }
+    });
+}
+
+#[update(name = "runHousekeeping")]
+fn run_housekeeping() -> Result<(), String> {
+    if ic_cdk::api::caller() != ic_cdk::id() {
+        return Err("Only the canister itself runs housekeeping".to_string());
+    }
+    housekeeping(time());
+    Ok(())
+}
+
+fn housekeeping(now: u64) {
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(dataset_id) = deletion::due(&map.stable, now) {
+            purge_dataset(&mut map, dataset_id, now);
+        }
+    });
+    if STATE.with(|map| map.borrow().next_purge_at > now) {
+        return;
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let purged = retention::purge(&mut map.stable, retention::PURGE_CHUNK, now);
+        let removed: u32 = purged.iter().map(|x| x.removed_entries).sum();
+        for record in purged.iter() {
+            refresh_views(&mut map, record.dataset_id);
+            drop_export_sessions(&mut map, record.dataset_id);
+            touch_dataset(&mut map, record.dataset_id);
+        }
+        map.next_purge_at = if removed as usize >= retention::PURGE_CHUNK { now } else { now + retention::PURGE_INTERVAL };
+    });
+}
+
+#[update(name = "setRetentionPolicy")]
+fn set_retention_policy(dataset_id: u32, rules: Vec<RetentionRule>) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can change the retention policy".to_string());
+    }
+    let dataset = get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    retention::validate(&dataset, &rules)?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        // Rules purge data, so they need the quorum; dropping them purges nothing
+        if rules.is_empty() {
+            map.stable.retention_policies.remove(&dataset_id);
+        } else {
+            ownership::approve(&mut map.stable, dataset_id, OwnerAction::SetRetentionPolicy(rules.clone()), caller, time())?;
+            map.stable.retention_policies.insert(dataset_id, rules);
+        }
+        // Picked up on the next housekeeping run
+        map.next_purge_at = 0;
+        Ok(())
+    })
+}
+
+#[query(name = "getRetentionPolicy")]
+fn get_retention_policy(dataset_id: u32) -> Vec<RetentionRule> {
+    STATE.with(|map| map.borrow().stable.retention_policies.get(&dataset_id).cloned().unwrap_or_default())
+}
+
+#[query(name = "previewRetentionPurge")]
+fn preview_retention_purge(dataset_id: u32) -> Result<PurgePreview, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can preview purges".to_string());
+    }
+    STATE.with(|map| {
+        let map = map.borrow();
+        // Evaluated at the time of the next run, on the data as it is now
+        let next_purge_at = std::cmp::max(map.next_purge_at, time());
+        let expired = retention::expired_count(&map.stable, dataset_id, next_purge_at);
+        Ok(PurgePreview {
+            dataset_id,
+            expired_entries: expired as u32,
+            next_purge_entries: retention::next_purge_entries(&map.stable, dataset_id, next_purge_at) as u32,
+            next_purge_at,
+        })
+    })
+}
+
+#[query(name = "getPurgeLog")]
+fn get_purge_log(dataset_id: u32) -> Result<Vec<PurgeRecord>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can review the purge log".to_string());
+    }
+    Ok(STATE.with(|map| map.borrow().stable.purge_log.get(&dataset_id).cloned().unwrap_or_default()))
+}
+
+#[query(name = "getPrivacyPolicy")]
+fn get_privacy_policy_query(dataset_id: u32) -> PrivacyPolicy {
+    get_privacy_policy(dataset_id).unwrap_or_default()
+}
+
+#[query(name = "getPrivacyBudget")]
+fn get_privacy_budget(dataset_id: u32) -> Option<PrivacyBudgetStatus> {
+    let caller = ic_cdk::api::caller();
+    let dp = get_privacy_policy(dataset_id)?.differential_privacy?;
+    let spent = STATE.with(|map| {
+        map.borrow().stable.privacy_budgets
+            .get(&dataset_id)
+            .and_then(|x| x.get(&caller).cloned())
+            .unwrap_or(0.0)
+    });
+    Some(PrivacyBudgetStatus {
+        budget: dp.budget_per_consumer,
+        spent,
+        remaining: (dp.budget_per_consumer - spent).max(0.0),
+        epsilon_per_query: dp.epsilon_per_query,
+    })
+}
+
+#[update(name = "resetPrivacyBudget")]
+fn reset_privacy_budget(dataset_id: u32, user: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can reset privacy budgets".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        ownership::approve(&mut map.stable, dataset_id, OwnerAction::ResetPrivacyBudget(user), caller, time())?;
+        if let Some(budgets) = map.stable.privacy_budgets.get_mut(&dataset_id) {
+            budgets.remove(&user);
+        }
+        Ok(())
+    })
+}
+
+// Consent
+#[update(name = "setConsent")]
+fn set_consent(dataset_id: u32, purpose: ConsentPurpose, mode: UpdateMode) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous identity cannot record consent".to_string());
+    }
+    get_dataset_by_dataset_id(dataset_id).ok_or("Dataset not found")?;
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        if consent::update(&mut map.stable, caller, dataset_id, purpose, mode, time()) {
+            refresh_views(&mut map, dataset_id);
+            drop_export_sessions(&mut map, dataset_id);
+            touch_dataset(&mut map, dataset_id);
+        }
+    });
+    Ok(())
+}
+
+#[query(name = "getMyConsents")]
+fn get_my_consents() -> Vec<(u32, Vec<ConsentPurpose>)> {
+    let caller = ic_cdk::api::caller();
+    STATE.with(|map| {
+        map.borrow().stable.consents
+            .iter()
+            .filter_map(|(id, consents)| consents.get(&caller).map(|purposes| (*id, purposes.clone())))
+            .sorted_by_key(|x| x.0)
+            .collect()
+    })
+}
+
+#[update(name = "setTierPurposes")]
+fn set_tier_purposes(dataset_id: u32, tier: String, purposes: Vec<ConsentPurpose>) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !is_dataset_owner(caller, dataset_id) {
+        return Err("Only the dataset owner can declare tier purposes".to_string());
+    }
+    STATE.with(|map| {
+        let mut map = map.borrow_mut();
+        let declared = map.stable.tier_purposes.entry(dataset_id).or_default();
+        declared.retain(|(x, _)| *x != tier);
+        if !purposes.is_empty() {
+            declared.push((tier, purposes.into_iter().sorted().dedup().collect()));
+        }
+        touch_dataset(&mut map, dataset_id);
+    });
+    Ok(())
+}
+
+#[query(name = "getTierPurposes")]
+fn get_tier_purposes(dataset_id: u32) -> Vec<(String, Vec<ConsentPurpose>)> {
+    STATE.with(|map| map.borrow().stable.tier_purposes.get(&dataset_id).cloned().unwrap_or_default())
+}
+
+#[query(name = "getConsentLog")]
+fn get_consent_log(dataset_id: u32) -> Result<Vec<ConsentEvent>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can review the consent log".to_string());
+    }
+    Ok(STATE.with(|map| map.borrow().stable.consent_log.get(&dataset_id).cloned().unwrap_or_default()))
+}
+
+// Query auditing
+#[query(name = "getAuditAlerts")]
+fn get_audit_alerts(dataset_id: u32) -> Result<Vec<AuditAlert>, String> {
+    let caller = ic_cdk::api::caller();
+    if !has_role(caller, dataset_id, roles::REVIEWERS) {
+        return Err("Only dataset owners, admins and auditors can review audit alerts".to_string());
+    }
+    Ok(STATE.with(|map| map.borrow().stable.audit_alerts.get(&dataset_id).cloned().unwrap_or_default()))
+}
+
+#[query(name = "getFlaggedConsumers")]
+fn get_flagged_consumers(dataset_id: u32) -> Result<Vec<(Principal, u32)>, String> {
+    let alerts = get_audit_alerts(dataset_id)?;
+    Ok(alerts
+        .iter()
+        .counts_by(|x| x.user)
+        .into_iter()
+        .map(|(user, count)| (user, count as u32))
+        .sorted_by(|x, y| y.1.cmp(&x.1))
+        .collect())
+}
@@ -0,0 +1,207 @@
+use crate::pseudonym::hmac_sha256;
+use crate::types::*;
+use itertools::Itertools;
+
+// Unmapped categories and unparsable values are generalized all the way
+const SUPPRESSED: &str = "*";
+
+pub fn validate(config: &DatasetConfiguration, policy: &MaskingPolicy) -> Result<(), String> {
+    if let Some(name) = policy.profiles.iter().map(|x| &x.name).duplicates().next() {
+        return Err(format!("Duplicate masking profile {}", name));
+    }
+    let profile_names: Vec<&String> = policy.profiles.iter().map(|x| &x.name).collect();
+    if let Some(name) = policy.tier_profiles.iter().map(|x| &x.1).chain(policy.default_profile.iter()).find(|x| !profile_names.contains(x)) {
+        return Err(format!("Unknown masking profile {}", name));
+    }
+    for mask in policy.profiles.iter().flat_map(|x| x.masks.iter()) {
+        let dimension = config.dimensions
+            .iter()
+            .find(|dim| dim.dimension_id == mask.dimension_id)
+            .ok_or(format!("Unknown dimension {}", mask.dimension_id))?;
+        let numerical = dimension.dimension_type.is_metric();
+        match &mask.rule {
+            MaskRule::Redact => {},
+            MaskRule::Bucket(0) => return Err("Bucket width must be at least 1".to_string()),
+            MaskRule::Bucket(_) if !numerical => return Err(format!("Dimension {} is not numerical", dimension.title)),
+            MaskRule::GeoRound(grid) if !grid.is_finite() || *grid <= 0.0 => return Err("Grid size must be positive".to_string()),
+            MaskRule::GeoRound(_) if dimension.dimension_type != DimensionType::Geolocation => {
+                return Err(format!("Dimension {} is not a geolocation", dimension.title));
+            },
+            MaskRule::Hash | MaskRule::Truncate(_) | MaskRule::Generalize(_) if numerical => {
+                return Err(format!("Dimension {} is numerical, bucket it instead", dimension.title));
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+fn geo_round(text: &str, grid: f64) -> Option<String> {
+    let (lat, lng) = text.split_once(',')?;
+    let lat: f64 = lat.trim().parse().ok()?;
+    let lng: f64 = lng.trim().parse().ok()?;
+    let round = |x: f64| (x / grid).floor() * grid;
+    Some(format!("{},{}", round(lat), round(lng)))
+}
+
+// The masks of one consumer, from the profile of their first held tier in policy order.
+#[derive(Clone, Debug, Default)]
+pub struct Masking {
+    pub masks: Vec<ColumnMask>,
+    key: Vec<u8>,
+}
+
+impl Masking {
+    pub fn new(policy: Option<&MaskingPolicy>, tiers: &[String], key: &[u8]) -> Self {
+        let profile = policy.and_then(|policy| {
+            let name = policy.tier_profiles
+                .iter()
+                .find(|(tier, _)| tiers.contains(tier))
+                .map(|(_, name)| name)
+                .or(policy.default_profile.as_ref())?;
+            policy.profiles.iter().find(|x| &x.name == name)
+        });
+        Masking {
+            masks: profile.map(|x| x.masks.clone()).unwrap_or_default(),
+            key: key.to_vec(),
+        }
+    }
+
+    pub fn is_masked(&self, dimension_id: u8) -> bool {
+        self.masks.iter().any(|x| x.dimension_id == dimension_id)
+    }
+
+    fn mask(&self, rule: &MaskRule, value: Value) -> Option<Value> {
+        match (rule, value) {
+            (MaskRule::Redact, _) => None,
+            // An unkeyed hash of low-entropy values is reversible by enumeration
+            (MaskRule::Hash, _) if self.key.is_empty() => None,
+            (MaskRule::Bucket(width), Value::Metric(x)) => Some(Value::Metric(x - x % width)),
+            (MaskRule::Hash, value) => {
+                let digest = hmac_sha256(&self.key, value.to_string().as_bytes());
+                Some(Value::Attribute(digest.iter().take(8).map(|x| format!("{:02x}", x)).join("")))
+            },
+            (MaskRule::Truncate(length), Value::Attribute(text)) => Some(Value::Attribute(text.chars().take(*length as usize).collect())),
+            (MaskRule::Generalize(parents), Value::Attribute(text)) => Some(Value::Attribute(
+                parents
+                    .iter()
+                    .find(|(child, _)| *child == text)
+                    .map(|(_, parent)| parent.clone())
+                    .unwrap_or_else(|| SUPPRESSED.to_string()),
+            )),
+            (MaskRule::GeoRound(grid), Value::Attribute(text)) => Some(Value::Attribute(
+                geo_round(&text, *grid).unwrap_or_else(|| SUPPRESSED.to_string()),
+            )),
+            (_, value) => Some(value),
+        }
+    }
+
+    pub fn apply(&self, entry: DatasetEntry) -> DatasetEntry {
+        if self.masks.is_empty() {
+            return entry;
+        }
+        DatasetEntry {
+            values: entry.values
+                .into_iter()
+                .filter_map(|val| match self.masks.iter().find(|x| x.dimension_id == val.dimension_id) {
+                    Some(mask) => self.mask(&mask.rule, val.value).map(|value| DatasetValue { dimension_id: val.dimension_id, value }),
+                    None => Some(val),
+                })
+                .collect(),
+            ..entry
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    const NAME: u8 = 1;
+    const AGE: u8 = 2;
+    const ZIP: u8 = 3;
+    const DIAGNOSIS: u8 = 4;
+    const HOME: u8 = 5;
+
+    fn patient() -> DatasetEntry {
+        let text = |x: &str| Value::Attribute(x.to_string());
+        DatasetEntry {
+            id: RecordKey::Id(1),
+            producer: Principal::anonymous(),
+            values: vec![
+                DatasetValue { dimension_id: NAME, value: text("Ada") },
+                DatasetValue { dimension_id: AGE, value: Value::Metric(47) },
+                DatasetValue { dimension_id: ZIP, value: text("80331") },
+                DatasetValue { dimension_id: DIAGNOSIS, value: text("influenza") },
+                DatasetValue { dimension_id: HOME, value: text("48.137, 11.575") },
+            ],
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn masked(masks: Vec<ColumnMask>, key: &[u8]) -> Vec<(u8, Value)> {
+        let masking = Masking { masks, key: key.to_vec() };
+        masking.apply(patient()).values.into_iter().map(|x| (x.dimension_id, x.value)).collect()
+    }
+
+    fn value_of(values: &[(u8, Value)], dimension_id: u8) -> Option<Value> {
+        values.iter().find(|x| x.0 == dimension_id).map(|x| x.1.clone())
+    }
+
+    fn text(x: &str) -> Option<Value> {
+        Some(Value::Attribute(x.to_string()))
+    }
+
+    #[test]
+    fn rules_coarsen_their_column_only() {
+        let values = masked(vec![
+            ColumnMask { dimension_id: NAME, rule: MaskRule::Redact },
+            ColumnMask { dimension_id: AGE, rule: MaskRule::Bucket(10) },
+            ColumnMask { dimension_id: ZIP, rule: MaskRule::Truncate(2) },
+            ColumnMask { dimension_id: HOME, rule: MaskRule::GeoRound(0.5) },
+        ], &[]);
+        assert_eq!(value_of(&values, NAME), None);
+        assert_eq!(value_of(&values, AGE), Some(Value::Metric(40)));
+        assert_eq!(value_of(&values, ZIP), text("80"));
+        assert_eq!(value_of(&values, DIAGNOSIS), text("influenza"));
+        assert_eq!(value_of(&values, HOME), text("48,11.5"));
+    }
+
+    #[test]
+    fn unmapped_categories_are_suppressed() {
+        let respiratory = vec![("influenza".to_string(), "respiratory".to_string())];
+        let values = masked(vec![ColumnMask { dimension_id: DIAGNOSIS, rule: MaskRule::Generalize(respiratory) }], &[]);
+        assert_eq!(value_of(&values, DIAGNOSIS), text("respiratory"));
+        let values = masked(vec![ColumnMask { dimension_id: DIAGNOSIS, rule: MaskRule::Generalize(vec![]) }], &[]);
+        assert_eq!(value_of(&values, DIAGNOSIS), text(SUPPRESSED));
+    }
+
+    #[test]
+    fn hashing_needs_a_key() {
+        let mask = || vec![ColumnMask { dimension_id: NAME, rule: MaskRule::Hash }];
+        assert_eq!(value_of(&masked(mask(), &[]), NAME), None);
+        let hashed = value_of(&masked(mask(), b"dataset-key"), NAME);
+        assert!(matches!(&hashed, Some(Value::Attribute(x)) if x.len() == 16 && x != "Ada"));
+        assert_eq!(value_of(&masked(mask(), b"dataset-key"), NAME), hashed);
+        assert_ne!(value_of(&masked(mask(), b"other-key"), NAME), hashed);
+    }
+
+    // The first held tier in policy order wins over the default profile
+    #[test]
+    fn profile_follows_the_tiers() {
+        let profile = |name: &str, dimension_id: u8| MaskingProfile {
+            name: name.to_string(),
+            masks: vec![ColumnMask { dimension_id, rule: MaskRule::Redact }],
+        };
+        let policy = MaskingPolicy {
+            profiles: vec![profile("clinical", ZIP), profile("public", NAME)],
+            tier_profiles: vec![("clinician".to_string(), "clinical".to_string())],
+            default_profile: Some("public".to_string()),
+        };
+        assert!(Masking::new(Some(&policy), &["clinician".to_string()], &[]).is_masked(ZIP));
+        assert!(Masking::new(Some(&policy), &[], &[]).is_masked(NAME));
+        assert!(Masking::new(None, &[], &[]).masks.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::tokens;
+use crate::types::*;
+use candid::de::IDLDeserialize;
+use candid::{CandidType, Principal};
+use itertools::Itertools;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Stable state as saved by the release before access control, templates and the rest landed
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct LegacyStableState {
+    pub datasets: HashMap<u32, LegacyDatasetConfiguration>,
+    pub dataset_values: HashMap<u32, Vec<DatasetEntry>>,
+    pub dataset_owners: HashMap<Principal, Vec<u32>>,
+    pub dataset_producers: HashMap<u32, Vec<ProducerState>>,
+    pub queries: HashMap<u32, Query>,
+    pub analytics_tokens: HashMap<Principal, LegacyAnalyticsToken>,
+    pub next_dataset_id: u32,
+    pub next_query_id: u32,
+}
+
+// One plaintext token per principal, never checked for expiry
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct LegacyAnalyticsToken {
+    pub token : String,
+    pub lifetime : u32,
+    pub created_at : u64,
+    pub expire_at : u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct LegacyDatasetConfiguration {
+    pub name : String,
+    pub asset_id : String,
+    pub description: String,
+    pub jupyter_notebook: Option<String>,
+    pub dimensions: Vec<DatasetDimension>,
+    pub is_active: bool,
+    pub category: Vec<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl From<LegacyDatasetConfiguration> for DatasetConfiguration {
+    fn from(legacy: LegacyDatasetConfiguration) -> Self {
+        DatasetConfiguration {
+            name: legacy.name,
+            asset_id: legacy.asset_id,
+            description: legacy.description,
+            jupyter_notebook: legacy.jupyter_notebook,
+            dimensions: legacy.dimensions,
+            is_active: legacy.is_active,
+            category: legacy.category,
+            official_templates: vec![],
+            created_at: legacy.created_at,
+            updated_at: legacy.updated_at,
+        }
+    }
+}
+
+// Only the hash is kept from here on. The old expiry was never enforced, so the lifetime starts
+// at the upgrade, and the token keeps its unrestricted reach.
+fn upgrade_tokens(state: &mut StableState, tokens: HashMap<Principal, LegacyAnalyticsToken>, now: u64) {
+    for (user, legacy) in tokens.into_iter().sorted_by_key(|(_, x)| x.created_at) {
+        state.next_token_id += 1;
+        let record = AnalyticsToken {
+            id: state.next_token_id,
+            token_hash: tokens::hash(&legacy.token),
+            lifetime: legacy.lifetime,
+            created_at: legacy.created_at,
+            expire_at: now.saturating_add((legacy.lifetime as u64).saturating_mul(1_000_000_000)),
+            revoked_at: None,
+            scope: TokenScope {
+                datasets: None,
+                operations: vec![TokenOperation::Analytics, TokenOperation::Download],
+                columns: None,
+            },
+            usage: TokenUsage::default(),
+        };
+        state.token_index.insert(record.token_hash.clone(), user);
+        state.analytics_tokens.insert(user, vec![record]);
+    }
+}
+
+// The upgrading controller becomes the first admin, as the installer does in init
+fn upgrade(legacy: LegacyStableState, controller: Principal, now: u64) -> StableState {
+    let mut state = StableState {
+        datasets: legacy.datasets.into_iter().map(|(id, x)| (id, x.into())).collect(),
+        dataset_values: legacy.dataset_values,
+        dataset_owners: legacy.dataset_owners,
+        dataset_producers: legacy.dataset_producers,
+        queries: legacy.queries,
+        admins: vec![controller],
+        next_dataset_id: legacy.next_dataset_id,
+        next_query_id: legacy.next_query_id,
+        ..Default::default()
+    };
+    upgrade_tokens(&mut state, legacy.analytics_tokens, now);
+    state
+}
+
+fn decode<T: for<'de> Deserialize<'de> + CandidType>(bytes: &[u8]) -> Result<T, String> {
+    let mut de = IDLDeserialize::new(bytes).map_err(|e| format!("{:?}", e))?;
+    de.get_value::<T>().map_err(|e| format!("{:?}", e))
+}
+
+// Stable memory holds either the current shape or the legacy one; trailing bytes are ignored
+pub fn restore(bytes: &[u8], controller: Principal, now: u64) -> Result<StableState, String> {
+    decode::<StableState>(bytes).or_else(|current| {
+        decode::<LegacyStableState>(bytes)
+            .map(|legacy| upgrade(legacy, controller, now))
+            .map_err(|legacy| format!("Stable state matches no known shape: {}; legacy: {}", current, legacy))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::encode_one;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn controller() -> Principal {
+        Principal::from_slice(&[7; 29])
+    }
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    // Encoded the way the baseline canister saved it, padded like a stable memory page
+    fn baseline_bytes() -> Vec<u8> {
+        let owner = owner();
+        let dataset = LegacyDatasetConfiguration {
+            name: "Survey".to_string(),
+            asset_id: "asset".to_string(),
+            description: "".to_string(),
+            jupyter_notebook: None,
+            dimensions: vec![DatasetDimension {
+                dimension_id: 1,
+                title: "Age".to_string(),
+                dimension_type: DimensionType::Numerical,
+            }],
+            is_active: true,
+            category: vec!["health".to_string()],
+            created_at: 10,
+            updated_at: 20,
+        };
+        let entry = DatasetEntry {
+            id: RecordKey::Id(1),
+            producer: owner,
+            values: vec![DatasetValue { dimension_id: 1, value: Value::Metric(42) }],
+            created_at: 10,
+            updated_at: 10,
+        };
+        let legacy = LegacyStableState {
+            datasets: HashMap::from([(1, dataset)]),
+            dataset_values: HashMap::from([(1, vec![entry])]),
+            dataset_owners: HashMap::from([(owner, vec![1])]),
+            dataset_producers: HashMap::new(),
+            queries: HashMap::new(),
+            analytics_tokens: HashMap::from([(owner, LegacyAnalyticsToken {
+                token: "legacy-secret".to_string(),
+                lifetime: 3600,
+                created_at: 10,
+                expire_at: 10,
+            })]),
+            next_dataset_id: 1,
+            next_query_id: 0,
+        };
+        let mut bytes = encode_one(&legacy).unwrap();
+        bytes.resize(bytes.len() + 64, 0);
+        bytes
+    }
+
+    #[test]
+    fn restores_baseline_state() {
+        let state = restore(&baseline_bytes(), controller(), NOW).unwrap();
+        assert_eq!(state.datasets[&1].name, "Survey");
+        assert!(state.datasets[&1].official_templates.is_empty());
+        assert_eq!(state.dataset_values[&1][0].values[0].value, Value::Metric(42));
+        assert_eq!(state.dataset_owners[&owner()], vec![1]);
+        assert_eq!(state.next_dataset_id, 1);
+        assert_eq!(state.admins, vec![controller()]);
+    }
+
+    #[test]
+    fn converts_baseline_tokens() {
+        let state = restore(&baseline_bytes(), controller(), NOW).unwrap();
+        let token = &state.analytics_tokens[&owner()][0];
+        assert_eq!(token.id, 1);
+        assert_eq!(state.next_token_id, 1);
+        assert_eq!(token.expire_at, NOW + 3600 * 1_000_000_000);
+        let consumer = tokens::resolve(&state, "legacy-secret", 1, TokenOperation::Download, NOW + 1).unwrap();
+        assert_eq!(consumer.principal, owner());
+        assert_eq!(consumer.columns, None);
+    }
+
+    #[test]
+    fn restores_current_state_unchanged() {
+        let state = StableState { next_query_id: 5, admins: vec![Principal::anonymous()], ..Default::default() };
+        let bytes = encode_one(&state).unwrap();
+        assert_eq!(restore(&bytes, controller(), NOW).unwrap(), state);
+    }
+
+    #[test]
+    fn rejects_unknown_state() {
+        let bytes = encode_one("not a state").unwrap();
+        assert!(restore(&bytes, controller(), NOW).is_err());
+    }
+}
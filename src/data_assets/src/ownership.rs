@@ -0,0 +1,129 @@
+use crate::roles;
+use crate::types::*;
+use ic_cdk::export::Principal;
+use itertools::Itertools;
+
+pub const OFFER_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+pub const PROPOSAL_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+pub fn owners(state: &StableState, dataset_id: u32) -> Vec<Principal> {
+    state.dataset_owners
+        .iter()
+        .filter(|(_, datasets)| datasets.contains(&dataset_id))
+        .map(|(user, _)| *user)
+        .sorted()
+        .collect()
+}
+
+// Never more than the owners left to approve
+pub fn quorum(state: &StableState, dataset_id: u32) -> usize {
+    let configured = state.owner_quorums.get(&dataset_id).cloned().unwrap_or(1) as usize;
+    std::cmp::max(1, std::cmp::min(configured, owners(state, dataset_id).len()))
+}
+
+pub fn validate_quorum(state: &StableState, dataset_id: u32, quorum: u32) -> Result<(), String> {
+    let count = owners(state, dataset_id).len();
+    if quorum == 0 || quorum as usize > count {
+        return Err(format!("Quorum must be between 1 and the {} owner(s) of the dataset", count));
+    }
+    Ok(())
+}
+
+// Records one owner's approval; Ok once the quorum of current owners approved the same action.
+pub fn approve(state: &mut StableState, dataset_id: u32, action: OwnerAction, owner: Principal, now: u64) -> Result<(), String> {
+    let owners = owners(state, dataset_id);
+    let quorum = quorum(state, dataset_id);
+    let proposals = state.owner_proposals.entry(dataset_id).or_default();
+    proposals.retain(|x| x.expires_at > now);
+    let index = match proposals.iter().position(|x| x.action == action) {
+        Some(index) => index,
+        None => {
+            proposals.push(OwnerProposal { dataset_id, action, approvals: vec![], created_at: now, expires_at: now + PROPOSAL_TTL });
+            proposals.len() - 1
+        },
+    };
+    let proposal = &mut proposals[index];
+    if !proposal.approvals.contains(&owner) {
+        proposal.approvals.push(owner);
+    }
+    let approved = proposal.approvals.iter().filter(|x| owners.contains(x)).count();
+    if approved >= quorum {
+        proposals.remove(index);
+        Ok(())
+    } else {
+        Err(format!("Approved by {} of {} required owners", approved, quorum))
+    }
+}
+
+pub fn offer(state: &mut StableState, dataset_id: u32, from: Principal, to: Principal, now: u64) -> Result<OwnershipOffer, String> {
+    if owners(state, dataset_id).contains(&to) {
+        return Err("Recipient already owns the dataset".to_string());
+    }
+    let offers = state.ownership_offers.entry(dataset_id).or_default();
+    offers.retain(|x| x.expires_at > now && !(x.from == from && x.to == to));
+    let offer = OwnershipOffer { dataset_id, from, to, offered_at: now, expires_at: now + OFFER_TTL };
+    offers.push(offer.clone());
+    Ok(offer)
+}
+
+// The recipient takes over the share of the owner who made the offer
+pub fn accept(state: &mut StableState, dataset_id: u32, from: Principal, to: Principal, now: u64) -> Result<(), String> {
+    let offers = state.ownership_offers.entry(dataset_id).or_default();
+    offers.retain(|x| x.expires_at > now);
+    let index = offers
+        .iter()
+        .position(|x| x.from == from && x.to == to)
+        .ok_or("No pending ownership offer")?;
+    offers.remove(index);
+    if !owners(state, dataset_id).contains(&from) {
+        return Err("The offering principal no longer owns the dataset".to_string());
+    }
+    roles::update(state, dataset_id, to, DatasetRole::Owner, UpdateMode::Add, from, now)?;
+    roles::update(state, dataset_id, from, DatasetRole::Owner, UpdateMode::Remove, from, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    // Two owners who both must approve
+    fn co_owned() -> StableState {
+        let mut state = StableState::default();
+        state.dataset_owners.insert(principal(1), vec![1]);
+        state.dataset_owners.insert(principal(2), vec![1]);
+        state.owner_quorums.insert(1, 2);
+        state
+    }
+
+    #[test]
+    fn adding_an_owner_needs_the_quorum() {
+        let mut state = co_owned();
+        let action = OwnerAction::AddOwner(principal(3));
+        assert!(approve(&mut state, 1, action.clone(), principal(1), 0).is_err());
+        // The candidate is not an owner yet, so their approval does not count
+        assert!(approve(&mut state, 1, action.clone(), principal(3), 0).is_err());
+        assert!(approve(&mut state, 1, action, principal(2), 0).is_ok());
+    }
+
+    #[test]
+    fn lowering_the_quorum_needs_the_quorum() {
+        let mut state = co_owned();
+        assert!(approve(&mut state, 1, OwnerAction::SetQuorum(1), principal(1), 0).is_err());
+        assert_eq!(quorum(&state, 1), 2);
+        assert!(approve(&mut state, 1, OwnerAction::SetQuorum(1), principal(2), 0).is_ok());
+    }
+
+    // Approving a one-year rule does not carry over to a one-day rule
+    #[test]
+    fn retention_rules_are_approved_as_proposed() {
+        let mut state = co_owned();
+        let rules = |days| OwnerAction::SetRetentionPolicy(vec![RetentionRule::CreatedAt { max_age_days: days }]);
+        assert!(approve(&mut state, 1, rules(365), principal(1), 0).is_err());
+        assert!(approve(&mut state, 1, rules(1), principal(2), 0).is_err());
+        assert!(approve(&mut state, 1, rules(365), principal(2), 0).is_ok());
+    }
+}
@@ -0,0 +1,44 @@
+use crate::consent;
+use crate::masking::Masking;
+use crate::pseudonym::Pseudonymizer;
+use crate::rows;
+use crate::types::*;
+use ic_cdk::export::Principal;
+
+// Everything applied to dataset records before they reach one consumer: consent for the
+// purposes of the call, their row-level scope, column masking of their tier, then pseudonymous principals.
+pub struct EntryPolicy {
+    pub purposes: Vec<ConsentPurpose>,
+    pub rows: Vec<RowPredicate>,
+    pub masking: Masking,
+    pub pseudonyms: Pseudonymizer,
+}
+
+impl EntryPolicy {
+    pub fn new(state: &StableState, dataset_id: u32, consumer: Principal, tiers: &[String], purpose: ConsentPurpose, now: u64) -> Self {
+        let privacy = state.privacy_policies.get(&dataset_id).cloned().unwrap_or_default();
+        EntryPolicy {
+            purposes: consent::required_purposes(state, dataset_id, tiers, purpose),
+            rows: rows::predicates_for(state.row_policies.get(&dataset_id).map(|x| x.as_slice()).unwrap_or_default(), consumer, tiers),
+            masking: Masking::new(state.masking_policies.get(&dataset_id), tiers, &state.pseudonym_salt),
+            pseudonyms: Pseudonymizer::new(&state.pseudonym_salt, &privacy.pseudonymization, dataset_id, consumer, now),
+        }
+    }
+
+    // Input of analytics: principals never leave the canister there
+    pub fn visible_entries(&self, state: &StableState, dataset_id: u32) -> Vec<DatasetEntry> {
+        let entries = state.dataset_values.get(&dataset_id).cloned().unwrap_or_default();
+        consent::filter_entries(state, dataset_id, &entries, &self.purposes)
+            .into_iter()
+            .filter(|x| rows::matches(x, &self.rows))
+            .map(|x| self.masking.apply(x))
+            .collect()
+    }
+
+    pub fn released_entries(&self, state: &StableState, dataset_id: u32) -> Vec<DatasetEntry> {
+        self.visible_entries(state, dataset_id)
+            .into_iter()
+            .map(|x| self.pseudonyms.apply(x))
+            .collect()
+    }
+}
@@ -0,0 +1,418 @@
+use crate::pseudonym;
+use crate::random::Rng;
+use crate::types::*;
+use itertools::Itertools;
+use std::collections::HashMap;
+
+impl Default for PrivacyPolicy {
+    fn default() -> Self {
+        PrivacyPolicy {
+            k_threshold: 5,
+            tier_thresholds: vec![],
+            l_diversity: None,
+            secondary_suppression: true,
+            query_auditing: AuditMode::Flag,
+            differential_privacy: None,
+            pseudonymization: PseudonymConfig::default(),
+        }
+    }
+}
+
+// Suppression rules applied to one analytics call; only enforced for GDPR-restricted access.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DisclosureControl {
+    pub k_threshold : Option<u32>,
+    pub l_diversity : Option<LDiversity>,
+    pub secondary_suppression : bool,
+}
+
+impl DisclosureControl {
+    // Held tiers with an override use the lowest one, otherwise the dataset threshold applies.
+    pub fn new(policy: &PrivacyPolicy, access: &DatasetAccess) -> Self {
+        if !access.is_gdpr {
+            return DisclosureControl::default();
+        }
+        let k_threshold = policy.tier_thresholds
+            .iter()
+            .filter(|(tier, _)| access.tiers.contains(tier))
+            .map(|(_, k)| *k)
+            .min()
+            .unwrap_or(policy.k_threshold);
+        DisclosureControl {
+            k_threshold: Some(k_threshold),
+            l_diversity: policy.l_diversity.clone(),
+            secondary_suppression: policy.secondary_suppression,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.k_threshold.is_some()
+    }
+}
+
+pub fn validate_policy(config: &DatasetConfiguration, policy: &PrivacyPolicy) -> Result<(), String> {
+    pseudonym::validate(&policy.pseudonymization)?;
+    if let Some(ld) = &policy.l_diversity {
+        if !config.dimensions.iter().any(|dim| dim.dimension_id == ld.dimension_id) {
+            return Err(format!("Unknown sensitive dimension {}", ld.dimension_id));
+        }
+        if ld.l == 0 {
+            return Err("l must be at least 1".to_string());
+        }
+    }
+    match &policy.differential_privacy {
+        Some(dp) => validate_dp_config(config, dp),
+        None => Ok(()),
+    }
+}
+
+// Any line of cells (all attributes fixed but one, or the whole table) with exactly one
+// suppressed cell would let it be recovered from a marginal total: hide the next smallest too.
+fn secondary_suppression(groups: &[AnalyticsType], suppressed: &mut [bool]) {
+    let width = groups.iter().map(|x| x.attributes.len()).max().unwrap_or(0);
+    let mut lines: Vec<Vec<usize>> = vec![(0..groups.len()).collect()];
+    for position in 0..width {
+        let by_line = groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| {
+                let key = group.attributes
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != position)
+                    .map(|(_, val)| val.to_string())
+                    .join("--");
+                (key, index)
+            })
+            .into_group_map();
+        lines.extend(by_line.into_values());
+    }
+    loop {
+        let mut changed = false;
+        for line in lines.iter() {
+            if line.iter().filter(|i| suppressed[**i]).count() == 1 {
+                if let Some(next) = line.iter().filter(|i| !suppressed[**i]).min_by_key(|i| groups[**i].count) {
+                    suppressed[*next] = true;
+                    changed = true;
+                }
+            }
+        }
+        if !changed { break; }
+    }
+}
+
+// `diversity` holds the number of distinct sensitive values per group key.
+pub fn suppress(groups: &mut Vec<AnalyticsType>, diversity: &HashMap<String, usize>, control: &DisclosureControl) {
+    let mut suppressed: Vec<bool> = groups
+        .iter()
+        .map(|group| {
+            let below_k = control.k_threshold.map(|k| group.count < k).unwrap_or(false);
+            let below_l = control.l_diversity
+                .as_ref()
+                .map(|ld| (diversity.get(&group.group_key).cloned().unwrap_or(0) as u32) < ld.l)
+                .unwrap_or(false);
+            below_k || below_l
+        })
+        .collect();
+    if control.secondary_suppression && suppressed.iter().any(|x| *x) {
+        secondary_suppression(groups, &mut suppressed);
+    }
+    let mut flags = suppressed.into_iter();
+    groups.retain(|_| !flags.next().unwrap_or(false));
+}
+
+pub fn validate_dp_config(config: &DatasetConfiguration, dp: &DifferentialPrivacyConfig) -> Result<(), String> {
+    let is_valid = dp.epsilon_per_query.is_finite() && dp.epsilon_per_query > 0.0 && dp.budget_per_consumer >= dp.epsilon_per_query;
+    if !is_valid {
+        return Err("Epsilon must be positive and no larger than the consumer budget".to_string());
+    }
+    if let NoiseMechanism::Gaussian { delta } = dp.mechanism {
+        if !(delta > 0.0 && delta < 1.0) {
+            return Err("Delta must be between 0 and 1".to_string());
+        }
+        // The classic Gaussian calibration only holds below 1
+        if dp.epsilon_per_query >= 1.0 {
+            return Err("The Gaussian mechanism needs an epsilon below 1".to_string());
+        }
+    }
+    if let Some(delta) = dp.threshold_delta {
+        if !(delta > 0.0 && delta < 1.0) {
+            return Err("Threshold delta must be between 0 and 1".to_string());
+        }
+    }
+    for bound in dp.bounds.iter() {
+        let is_numerical = config.dimensions
+            .iter()
+            .any(|dim| dim.dimension_id == bound.dimension_id && dim.dimension_type == DimensionType::Numerical);
+        if !is_numerical {
+            return Err(format!("Dimension {} is not Numerical", bound.dimension_id));
+        }
+        if bound.lower > bound.upper {
+            return Err(format!("Invalid bounds for dimension {}", bound.dimension_id));
+        }
+    }
+    Ok(())
+}
+
+pub fn clamp(value: &Value, dimension_id: u8, bounds: &[MetricBound]) -> Value {
+    match (value, bounds.iter().find(|x| x.dimension_id == dimension_id)) {
+        (Value::Metric(x), Some(bound)) => Value::Metric((*x).clamp(bound.lower, bound.upper)),
+        _ => value.clone(),
+    }
+}
+
+fn laplace(rng: &mut Rng, scale: f64) -> f64 {
+    let u = rng.next_f64() - 0.5;
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).max(f64::MIN_POSITIVE).ln()
+}
+
+fn gaussian(rng: &mut Rng, sigma: f64) -> f64 {
+    let u1 = rng.next_f64().max(f64::MIN_POSITIVE);
+    let u2 = rng.next_f64();
+    sigma * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+fn gaussian_sigma(sensitivity: f64, delta: f64, epsilon: f64) -> f64 {
+    sensitivity * (2.0 * (1.25 / delta).ln()).sqrt() / epsilon
+}
+
+fn noisy(rng: &mut Rng, mechanism: &NoiseMechanism, value: u32, sensitivity: f64, epsilon: f64) -> u32 {
+    let noise = match mechanism {
+        NoiseMechanism::Laplace => laplace(rng, sensitivity / epsilon),
+        NoiseMechanism::Gaussian { delta } => gaussian(rng, gaussian_sigma(sensitivity, *delta, epsilon)),
+    };
+    (value as f64 + noise).round().clamp(0.0, u32::MAX as f64) as u32
+}
+
+// A group of a single record clears this noisy count with probability at most delta, so which groups
+// are released does not tell which exist
+fn release_threshold(dp: &DifferentialPrivacyConfig, epsilon: f64) -> Option<f64> {
+    let tail = match dp.mechanism {
+        NoiseMechanism::Laplace => (1.0 / (2.0 * dp.threshold_delta?)).ln() / epsilon,
+        NoiseMechanism::Gaussian { delta } => {
+            let threshold_delta = dp.threshold_delta.unwrap_or(delta);
+            gaussian_sigma(1.0, delta, epsilon) * (2.0 * (1.0 / threshold_delta).ln()).sqrt()
+        },
+    };
+    Some(1.0 + tail)
+}
+
+// Refused before any budget is spent
+pub fn check_dp_query(dp: &DifferentialPrivacyConfig, query: &QueryInput) -> Result<(), String> {
+    if let Some(metric) = query.metrics.iter().find(|id| !dp.bounds.iter().any(|x| x.dimension_id == **id)) {
+        return Err(format!("No clamping bounds declared for metric {}", metric));
+    }
+    if !query.attributes.is_empty() && release_threshold(dp, dp.epsilon_per_query).is_none() {
+        return Err("Grouped queries need a threshold delta with the Laplace mechanism".to_string());
+    }
+    Ok(())
+}
+
+// Debits (Add) or refunds (Remove) one query worth of epsilon
+pub fn charge_budget(spent: &mut f64, dp: &DifferentialPrivacyConfig, mode: UpdateMode) -> Result<(), String> {
+    match mode {
+        UpdateMode::Add => {
+            if *spent + dp.epsilon_per_query > dp.budget_per_consumer + f64::EPSILON {
+                return Err("Privacy budget exhausted for this dataset".to_string());
+            }
+            *spent += dp.epsilon_per_query;
+        },
+        UpdateMode::Remove => *spent = (*spent - dp.epsilon_per_query).max(0.0),
+    }
+    Ok(())
+}
+
+// Groups are disjoint, so each group spends the full epsilon, split evenly between the count and every metric sum.
+// Grouped results only keep groups above the release threshold; ungrouped ones always have their total.
+// Exact pipeline sizes would leak true counts: only the number of released groups is kept.
+pub fn add_noise(result: AnalyticsSuperType, query: &QueryInput, dp: &DifferentialPrivacyConfig, control: &DisclosureControl, seed: u64) -> AnalyticsSuperType {
+    let mut rng = Rng::new(seed);
+    let epsilon = dp.epsilon_per_query / (1 + query.metrics.len()) as f64;
+    let mut groups = result.analytics;
+    if query.attributes.is_empty() && groups.is_empty() {
+        groups.push(AnalyticsType {
+            group_key: String::new(),
+            attributes: vec![],
+            attribute_ids: vec![],
+            metrics: query.metrics.iter().map(|id| (*id, 0)).collect(),
+            count: 0,
+        });
+    }
+    let mut analytics: Vec<AnalyticsType> = groups
+        .into_iter()
+        .map(|group| {
+            let count = noisy(&mut rng, &dp.mechanism, group.count, 1.0, epsilon);
+            let metrics = group.metrics
+                .iter()
+                .map(|(id, sum)| {
+                    let sensitivity = dp.bounds.iter().find(|x| x.dimension_id == *id).map(|x| x.upper).unwrap_or(0) as f64;
+                    (*id, noisy(&mut rng, &dp.mechanism, *sum, sensitivity.max(1.0), epsilon))
+                })
+                .collect();
+            AnalyticsType { count, metrics, ..group }
+        })
+        .collect();
+    if !query.attributes.is_empty() {
+        let threshold = release_threshold(dp, epsilon).unwrap_or(f64::INFINITY);
+        analytics.retain(|x| x.count as f64 >= threshold);
+    }
+    suppress(&mut analytics, &HashMap::new(), &DisclosureControl { l_diversity: None, ..control.clone() });
+    let released = analytics.len() as u32;
+    AnalyticsSuperType {
+        analytics,
+        counts: (0, 0, 0, 0, released),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One group of a sex x region breakdown
+    fn cell(sex: &str, region: &str, count: u32) -> AnalyticsType {
+        let attributes = vec![Value::Attribute(sex.to_string()), Value::Attribute(region.to_string())];
+        AnalyticsType {
+            group_key: attributes.iter().join("--"),
+            attributes,
+            attribute_ids: vec![1, 2],
+            metrics: HashMap::new(),
+            count,
+        }
+    }
+
+    fn table() -> Vec<AnalyticsType> {
+        vec![
+            cell("M", "N", 2), cell("M", "S", 30), cell("M", "W", 40),
+            cell("F", "N", 9), cell("F", "S", 50), cell("F", "W", 60),
+        ]
+    }
+
+    fn control(secondary_suppression: bool) -> DisclosureControl {
+        DisclosureControl { k_threshold: Some(5), l_diversity: None, secondary_suppression }
+    }
+
+    fn released(groups: &[AnalyticsType]) -> Vec<String> {
+        groups.iter().map(|x| x.group_key.clone()).collect()
+    }
+
+    #[test]
+    fn primary_suppression_alone_leaves_the_cell_recoverable() {
+        let mut groups = table();
+        suppress(&mut groups, &HashMap::new(), &control(false));
+        assert_eq!(groups.len(), 5);
+        assert!(!released(&groups).contains(&cell("M", "N", 0).group_key));
+    }
+
+    // M/N is below k; F/N, then M/S and F/S go with it so no row, column or total pins it down
+    #[test]
+    fn secondary_suppression_covers_every_margin() {
+        let mut groups = table();
+        suppress(&mut groups, &HashMap::new(), &control(true));
+        assert_eq!(released(&groups), vec![cell("M", "W", 0).group_key, cell("F", "W", 0).group_key]);
+    }
+
+    // A one-attribute breakdown has a single line, the total
+    #[test]
+    fn two_hidden_cells_protect_each_other() {
+        let by_region = |region: &str, count: u32| AnalyticsType {
+            group_key: region.to_string(),
+            attributes: vec![Value::Attribute(region.to_string())],
+            attribute_ids: vec![2],
+            ..cell("", "", count)
+        };
+        let mut groups = vec![by_region("N", 2), by_region("S", 3), by_region("W", 40)];
+        suppress(&mut groups, &HashMap::new(), &control(true));
+        assert_eq!(released(&groups), vec!["W".to_string()]);
+    }
+
+    #[test]
+    fn low_diversity_counts_as_primary() {
+        let mut groups = vec![cell("M", "N", 20), cell("F", "N", 30)];
+        let diversity = HashMap::from([(groups[0].group_key.clone(), 1), (groups[1].group_key.clone(), 3)]);
+        let control = DisclosureControl { l_diversity: Some(LDiversity { dimension_id: 3, l: 2 }), ..control(true) };
+        suppress(&mut groups, &diversity, &control);
+        // The whole-table line would expose the remaining group, so it goes too
+        assert!(groups.is_empty());
+    }
+
+    fn dp(mechanism: NoiseMechanism, threshold_delta: Option<f64>) -> DifferentialPrivacyConfig {
+        DifferentialPrivacyConfig { mechanism, epsilon_per_query: 0.5, budget_per_consumer: 1.0, bounds: vec![], threshold_delta }
+    }
+
+    fn by_sex(attributes: Vec<u8>) -> QueryInput {
+        QueryInput { dataset_id: 1, attributes, metrics: vec![], filters: vec![] }
+    }
+
+    fn mean_abs(samples: &[f64]) -> f64 {
+        samples.iter().map(|x| x.abs()).sum::<f64>() / samples.len() as f64
+    }
+
+    // E|Laplace(b)| = b and E|N(0, s)| = s * sqrt(2 / pi)
+    #[test]
+    fn noise_has_the_calibrated_scale() {
+        let mut rng = Rng::new(11);
+        let samples: Vec<f64> = (0..20_000).map(|_| laplace(&mut rng, 4.0)).collect();
+        assert!((mean_abs(&samples) - 4.0).abs() < 0.2);
+        let sigma = gaussian_sigma(1.0, 1e-5, 0.5);
+        assert!((sigma - 2.0 * (2.0 * 125_000f64.ln()).sqrt()).abs() < 1e-9);
+        let samples: Vec<f64> = (0..20_000).map(|_| gaussian(&mut rng, sigma)).collect();
+        assert!((mean_abs(&samples) / (sigma * (2.0 / std::f64::consts::PI).sqrt()) - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn each_query_charges_its_epsilon_until_exhausted() {
+        let config = dp(NoiseMechanism::Laplace, None);
+        let mut spent = 0.0;
+        charge_budget(&mut spent, &config, UpdateMode::Add).unwrap();
+        charge_budget(&mut spent, &config, UpdateMode::Add).unwrap();
+        assert_eq!(spent, 1.0);
+        assert_eq!(charge_budget(&mut spent, &config, UpdateMode::Add), Err("Privacy budget exhausted for this dataset".to_string()));
+        assert_eq!(spent, 1.0);
+        charge_budget(&mut spent, &config, UpdateMode::Remove).unwrap();
+        assert_eq!(spent, 0.5);
+    }
+
+    // The M/N cell holds one record: its noisy count stays far below the threshold
+    #[test]
+    fn single_records_do_not_reveal_their_group() {
+        let config = dp(NoiseMechanism::Laplace, Some(1e-6));
+        let result = AnalyticsSuperType { analytics: vec![cell("M", "N", 1), cell("F", "W", 500)], counts: (0, 0, 0, 0, 0) };
+        let noisy = add_noise(result, &by_sex(vec![1, 2]), &config, &DisclosureControl::default(), 3);
+        assert_eq!(released(&noisy.analytics), vec![cell("F", "W", 0).group_key]);
+        assert_eq!(noisy.counts, (0, 0, 0, 0, 1));
+    }
+
+    #[test]
+    fn ungrouped_queries_always_release_their_total() {
+        let config = dp(NoiseMechanism::Laplace, None);
+        let empty = AnalyticsSuperType { analytics: vec![], counts: (0, 0, 0, 0, 0) };
+        assert_eq!(add_noise(empty, &by_sex(vec![]), &config, &DisclosureControl::default(), 3).analytics.len(), 1);
+    }
+
+    #[test]
+    fn grouping_under_laplace_needs_a_threshold_delta() {
+        assert!(check_dp_query(&dp(NoiseMechanism::Laplace, None), &by_sex(vec![1])).is_err());
+        assert!(check_dp_query(&dp(NoiseMechanism::Laplace, None), &by_sex(vec![])).is_ok());
+        assert!(check_dp_query(&dp(NoiseMechanism::Gaussian { delta: 1e-5 }, None), &by_sex(vec![1])).is_ok());
+    }
+
+    #[test]
+    fn gaussian_epsilon_stays_below_one() {
+        let config = DatasetConfiguration {
+            name: "Survey".to_string(),
+            asset_id: String::new(),
+            description: String::new(),
+            jupyter_notebook: None,
+            dimensions: vec![],
+            is_active: true,
+            category: vec![],
+            official_templates: vec![],
+            created_at: 0,
+            updated_at: 0,
+        };
+        let gaussian = dp(NoiseMechanism::Gaussian { delta: 1e-5 }, None);
+        assert!(validate_dp_config(&config, &gaussian).is_ok());
+        let loose = DifferentialPrivacyConfig { epsilon_per_query: 1.0, budget_per_consumer: 2.0, ..gaussian };
+        assert!(validate_dp_config(&config, &loose).is_err());
+    }
+}
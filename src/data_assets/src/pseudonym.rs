@@ -0,0 +1,143 @@
+use crate::types::*;
+use ic_cdk::export::Principal;
+use sha2::{Digest, Sha256};
+
+const BLOCK_SIZE: usize = 64;
+// Trailing class byte of derived ids: a pseudonym never collides with a user's self-authenticating principal
+const DERIVED_ID_CLASS: u8 = 0x03;
+
+impl Default for PseudonymConfig {
+    fn default() -> Self {
+        PseudonymConfig { mode: PseudonymMode::Stable, scope: PseudonymScope::Consumer }
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.iter().map(|x| x ^ 0x36).collect::<Vec<u8>>());
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.iter().map(|x| x ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+pub fn validate(config: &PseudonymConfig) -> Result<(), String> {
+    match config.mode {
+        PseudonymMode::Rotating(0) => Err("Rotation period must be at least one second".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// Replaces the principals of entries handed to one consumer of one dataset.
+pub struct Pseudonymizer {
+    key: Vec<u8>,
+    context: Vec<u8>,
+    removed: bool,
+}
+
+impl Pseudonymizer {
+    pub fn new(salt: &[u8], config: &PseudonymConfig, dataset_id: u32, consumer: Principal, now: u64) -> Self {
+        let mut context = dataset_id.to_be_bytes().to_vec();
+        if config.scope == PseudonymScope::Consumer {
+            context.extend_from_slice(consumer.as_slice());
+        }
+        if let PseudonymMode::Rotating(period) = config.mode {
+            context.extend_from_slice(&(now / period.saturating_mul(1_000_000_000)).to_be_bytes());
+        }
+        Pseudonymizer {
+            key: salt.to_vec(),
+            context,
+            removed: config.mode == PseudonymMode::Removed || salt.is_empty(),
+        }
+    }
+
+    pub fn principal(&self, user: &Principal) -> Principal {
+        if self.removed {
+            return Principal::anonymous();
+        }
+        let mut message = self.context.clone();
+        message.push(user.as_slice().len() as u8);
+        message.extend_from_slice(user.as_slice());
+        let mut bytes = hmac_sha256(&self.key, &message);
+        bytes.truncate(28);
+        bytes.push(DERIVED_ID_CLASS);
+        Principal::from_slice(&bytes)
+    }
+
+    pub fn apply(&self, entry: DatasetEntry) -> DatasetEntry {
+        DatasetEntry {
+            id: match entry.id {
+                RecordKey::User(user) => RecordKey::User(self.principal(&user)),
+                key => key,
+            },
+            producer: self.principal(&entry.producer),
+            ..entry
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn user() -> Principal {
+        Principal::from_slice(&[7; 29])
+    }
+
+    fn consumer(seed: u8) -> Principal {
+        Principal::from_slice(&[seed; 29])
+    }
+
+    fn pseudonym(salt: &[u8], mode: PseudonymMode, scope: PseudonymScope, dataset_id: u32, consumer: Principal, now: u64) -> Principal {
+        Pseudonymizer::new(salt, &PseudonymConfig { mode, scope }, dataset_id, consumer, now).principal(&user())
+    }
+
+    // RFC 4231, test case 2
+    #[test]
+    fn hmac_matches_the_reference_vector() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        let hex: String = mac.iter().map(|x| format!("{:02x}", x)).collect();
+        assert_eq!(hex, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn stable_pseudonyms_do_not_change_over_time() {
+        let first = pseudonym(&[1; 32], PseudonymMode::Stable, PseudonymScope::Consumer, 1, consumer(2), 0);
+        assert_eq!(first, pseudonym(&[1; 32], PseudonymMode::Stable, PseudonymScope::Consumer, 1, consumer(2), 1_000 * SECOND));
+        assert_ne!(first, user());
+        assert_eq!(*first.as_slice().last().unwrap(), DERIVED_ID_CLASS);
+    }
+
+    #[test]
+    fn pseudonyms_are_keyed_by_the_salt() {
+        let salted = |salt: &[u8]| pseudonym(salt, PseudonymMode::Stable, PseudonymScope::Dataset, 1, consumer(2), 0);
+        assert_ne!(salted(&[1; 32]), salted(&[2; 32]));
+        // Without a salt anyone could recompute them, so nothing is released
+        assert_eq!(salted(&[]), Principal::anonymous());
+    }
+
+    #[test]
+    fn scope_decides_who_shares_a_pseudonym() {
+        let scoped = |scope: PseudonymScope, dataset_id, seed| pseudonym(&[1; 32], PseudonymMode::Stable, scope, dataset_id, consumer(seed), 0);
+        assert_eq!(scoped(PseudonymScope::Dataset, 1, 2), scoped(PseudonymScope::Dataset, 1, 3));
+        assert_ne!(scoped(PseudonymScope::Dataset, 1, 2), scoped(PseudonymScope::Dataset, 2, 2));
+        assert_ne!(scoped(PseudonymScope::Consumer, 1, 2), scoped(PseudonymScope::Consumer, 1, 3));
+    }
+
+    #[test]
+    fn rotating_pseudonyms_change_each_period() {
+        let at = |now| pseudonym(&[1; 32], PseudonymMode::Rotating(60), PseudonymScope::Consumer, 1, consumer(2), now);
+        assert_eq!(at(0), at(59 * SECOND));
+        assert_ne!(at(59 * SECOND), at(60 * SECOND));
+        assert_eq!(pseudonym(&[1; 32], PseudonymMode::Removed, PseudonymScope::Consumer, 1, consumer(2), 0), Principal::anonymous());
+    }
+}
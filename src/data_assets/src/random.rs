@@ -0,0 +1,44 @@
+use ic_cdk::api::management_canister::main::raw_rand;
+
+// SplitMix64: small, fast and fully determined by its seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, bound), bound > 0
+    pub fn below(&mut self, bound: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % bound;
+        loop {
+            let x = self.next_u64();
+            if x < zone { return x % bound; }
+        }
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub async fn random_bytes() -> Result<Vec<u8>, String> {
+    match raw_rand().await {
+        Ok((bytes,)) => Ok(bytes),
+        Err((_, msg)) => Err(format!("Could not fetch randomness: {}", msg)),
+    }
+}
+
+pub async fn random_seed() -> Result<u64, String> {
+    let bytes = random_bytes().await?;
+    Ok(bytes.iter().take(8).fold(0u64, |acc, byte| (acc << 8) | *byte as u64))
+}
@@ -0,0 +1,147 @@
+use crate::erasure;
+use crate::types::*;
+use itertools::Itertools;
+
+// Purges run from the housekeeping timer at this interval, or on the next run while a backlog remains
+pub const PURGE_INTERVAL: u64 = 60 * 60 * 1_000_000_000;
+// Entries removed per run, across all datasets, to stay well within the instruction limit
+pub const PURGE_CHUNK: usize = 1_000;
+
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+pub fn validate(config: &DatasetConfiguration, rules: &[RetentionRule]) -> Result<(), String> {
+    for rule in rules.iter() {
+        match rule {
+            RetentionRule::CreatedAt { max_age_days: 0 } | RetentionRule::Dimension { duration_days: 0, .. } => {
+                return Err("Retention periods must be at least one day".to_string());
+            },
+            RetentionRule::Dimension { dimension_id, .. } => {
+                let dimension = config.dimensions
+                    .iter()
+                    .find(|dim| dim.dimension_id == *dimension_id)
+                    .ok_or(format!("Unknown dimension {}", dimension_id))?;
+                if dimension.dimension_type != DimensionType::Timestamp {
+                    return Err(format!("Dimension {} is not a timestamp", dimension.title));
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+// Any rule is enough; entries without the timestamp of a dimension rule are kept by that rule.
+pub fn is_expired(entry: &DatasetEntry, rules: &[RetentionRule], now: u64) -> bool {
+    rules.iter().any(|rule| match rule {
+        RetentionRule::CreatedAt { max_age_days } => entry.created_at.saturating_add(*max_age_days as u64 * DAY) <= now,
+        RetentionRule::Dimension { dimension_id, duration_days } => entry.values
+            .iter()
+            .find(|val| val.dimension_id == *dimension_id)
+            .map(|val| match val.value {
+                Value::Metric(seconds) => (seconds as u64 * 1_000_000_000).saturating_add(*duration_days as u64 * DAY) <= now,
+                Value::Attribute(_) => false,
+            })
+            .unwrap_or(false),
+    })
+}
+
+pub fn expired_count(state: &StableState, dataset_id: u32, now: u64) -> usize {
+    let rules = state.retention_policies.get(&dataset_id).cloned().unwrap_or_default();
+    state.dataset_values
+        .get(&dataset_id)
+        .map(|values| values.iter().filter(|x| is_expired(x, &rules, now)).count())
+        .unwrap_or(0)
+}
+
+// This dataset's share of the next run: the chunk is spent on datasets in id order, like `purge` does
+pub fn next_purge_entries(state: &StableState, dataset_id: u32, now: u64) -> usize {
+    let mut remaining = PURGE_CHUNK;
+    for id in state.retention_policies.keys().cloned().sorted() {
+        let expired = std::cmp::min(expired_count(state, id, now), remaining);
+        if id == dataset_id {
+            return expired;
+        }
+        remaining -= expired;
+    }
+    0
+}
+
+// Removes at most `budget` expired entries, dataset by dataset, and logs what went.
+pub fn purge(state: &mut StableState, budget: usize, now: u64) -> Vec<PurgeRecord> {
+    let mut remaining = budget;
+    let mut records = vec![];
+    let dataset_ids: Vec<u32> = state.retention_policies.keys().cloned().sorted().collect();
+    for dataset_id in dataset_ids {
+        if remaining == 0 { break; }
+        let rules = state.retention_policies.get(&dataset_id).cloned().unwrap_or_default();
+        let values = match state.dataset_values.get_mut(&dataset_id) {
+            Some(values) => values,
+            None => continue,
+        };
+        let mut removed = vec![];
+        values.retain(|x| {
+            if removed.len() < remaining && is_expired(x, &rules, now) {
+                removed.push(x.clone());
+                false
+            } else {
+                true
+            }
+        });
+        if removed.is_empty() { continue; }
+        remaining -= removed.len();
+        let record = PurgeRecord {
+            dataset_id,
+            timestamp: now,
+            removed_entries: removed.len() as u32,
+            entries_digest: erasure::entries_digest(&removed),
+        };
+        state.purge_log.entry(dataset_id).or_default().push(record.clone());
+        records.push(record);
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    const NOW: u64 = 400 * DAY;
+
+    // `count` entries created on day zero, all past a one-day rule
+    fn stale_dataset(state: &mut StableState, dataset_id: u32, count: usize) {
+        let entries = (0..count as u32)
+            .map(|i| DatasetEntry { id: RecordKey::Id(i), producer: Principal::anonymous(), values: vec![], created_at: 0, updated_at: 0 })
+            .collect();
+        state.dataset_values.insert(dataset_id, entries);
+        state.retention_policies.insert(dataset_id, vec![RetentionRule::CreatedAt { max_age_days: 1 }]);
+    }
+
+    #[test]
+    fn preview_shares_the_chunk_like_the_purge() {
+        let mut state = StableState::default();
+        stale_dataset(&mut state, 1, PURGE_CHUNK - 10);
+        stale_dataset(&mut state, 2, 50);
+        stale_dataset(&mut state, 3, 5);
+        let previews: Vec<usize> = (1..=3).map(|id| next_purge_entries(&state, id, NOW)).collect();
+        assert_eq!(previews, vec![PURGE_CHUNK - 10, 10, 0]);
+        let purged: Vec<usize> = purge(&mut state, PURGE_CHUNK, NOW).iter().map(|x| x.removed_entries as usize).collect();
+        assert_eq!(purged, vec![PURGE_CHUNK - 10, 10]);
+    }
+
+    #[test]
+    fn dimension_rules_keep_entries_without_the_timestamp() {
+        let rules = [RetentionRule::Dimension { dimension_id: 2, duration_days: 30 }];
+        let visit = |value: Option<Value>| DatasetEntry {
+            id: RecordKey::Id(1),
+            producer: Principal::anonymous(),
+            values: value.map(|value| vec![DatasetValue { dimension_id: 2, value }]).unwrap_or_default(),
+            created_at: 0,
+            updated_at: 0,
+        };
+        let seconds = |days: u64| Value::Metric((days * DAY / 1_000_000_000) as u32);
+        assert!(is_expired(&visit(Some(seconds(370))), &rules, NOW));
+        assert!(!is_expired(&visit(Some(seconds(371))), &rules, NOW));
+        assert!(!is_expired(&visit(None), &rules, NOW));
+    }
+}
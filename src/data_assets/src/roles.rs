@@ -0,0 +1,142 @@
+use crate::types::*;
+use ic_cdk::export::Principal;
+use itertools::Itertools;
+
+pub const MANAGERS: &[DatasetRole] = &[DatasetRole::Owner, DatasetRole::Admin];
+pub const REVIEWERS: &[DatasetRole] = &[DatasetRole::Owner, DatasetRole::Admin, DatasetRole::Auditor];
+pub const WRITERS: &[DatasetRole] = &[DatasetRole::Owner, DatasetRole::Producer];
+pub const ANALYSTS: &[DatasetRole] = &[DatasetRole::Owner, DatasetRole::Analyst];
+
+// Owners and producers keep their original maps; admins, analysts and auditors live in `dataset_roles`.
+pub fn roles_of(state: &StableState, user: Principal, dataset_id: u32) -> Vec<DatasetRole> {
+    let mut roles = vec![];
+    if state.dataset_owners.get(&user).map(|x| x.contains(&dataset_id)).unwrap_or(false) {
+        roles.push(DatasetRole::Owner);
+    }
+    if state.dataset_producers.get(&dataset_id).map(|x| x.iter().any(|p| p.id == user && p.is_enabled)).unwrap_or(false) {
+        roles.push(DatasetRole::Producer);
+    }
+    roles.extend(
+        state.dataset_roles
+            .get(&dataset_id)
+            .into_iter()
+            .flatten()
+            .filter(|(x, _)| *x == user)
+            .map(|(_, role)| *role),
+    );
+    roles.into_iter().sorted().dedup().collect()
+}
+
+pub fn has_any(state: &StableState, user: Principal, dataset_id: u32, roles: &[DatasetRole]) -> bool {
+    roles_of(state, user, dataset_id).iter().any(|x| roles.contains(x))
+}
+
+pub fn members(state: &StableState, dataset_id: u32) -> Vec<(Principal, Vec<DatasetRole>)> {
+    let owners = state.dataset_owners.iter().filter(|(_, x)| x.contains(&dataset_id)).map(|(user, _)| *user);
+    let producers = state.dataset_producers.get(&dataset_id).into_iter().flatten().map(|x| x.id);
+    let others = state.dataset_roles.get(&dataset_id).into_iter().flatten().map(|(user, _)| *user);
+    owners
+        .chain(producers)
+        .chain(others)
+        .unique()
+        .sorted()
+        .map(|user| (user, roles_of(state, user, dataset_id)))
+        .filter(|(_, roles)| !roles.is_empty())
+        .collect()
+}
+
+// Owners assign every role, admins only producers and auditors. Analysts see every column, so
+// only owners grant it. Nobody grants a role to themselves; leaving one is always allowed.
+pub fn can_assign(actor: &[DatasetRole], role: DatasetRole, mode: &UpdateMode, is_self: bool) -> bool {
+    if is_self && *mode == UpdateMode::Add {
+        return false;
+    }
+    match role {
+        DatasetRole::Owner | DatasetRole::Admin | DatasetRole::Analyst => actor.contains(&DatasetRole::Owner),
+        _ => actor.iter().any(|x| MANAGERS.contains(x)),
+    }
+}
+
+pub fn log(state: &mut StableState, dataset_id: u32, user: Principal, role: DatasetRole, mode: UpdateMode, changed_by: Principal, now: u64) {
+    let change = RoleChange { dataset_id, user, role, mode, changed_by, timestamp: now };
+    state.role_history.entry(dataset_id).or_default().push(change);
+}
+
+pub fn update(state: &mut StableState, dataset_id: u32, user: Principal, role: DatasetRole, mode: UpdateMode, changed_by: Principal, now: u64) -> Result<(), String> {
+    let changed = match (role, &mode) {
+        (DatasetRole::Owner, UpdateMode::Add) => {
+            let owned = state.dataset_owners.entry(user).or_default();
+            let changed = !owned.contains(&dataset_id);
+            if changed { owned.push(dataset_id); }
+            changed
+        },
+        (DatasetRole::Owner, UpdateMode::Remove) => {
+            let owners = state.dataset_owners.values().filter(|x| x.contains(&dataset_id)).count();
+            let owned = state.dataset_owners.entry(user).or_default();
+            let changed = owned.contains(&dataset_id);
+            if changed && owners == 1 {
+                return Err("A dataset needs at least one owner".to_string());
+            }
+            owned.retain(|x| *x != dataset_id);
+            if owned.is_empty() { state.dataset_owners.remove(&user); }
+            changed
+        },
+        (DatasetRole::Producer, UpdateMode::Add) => {
+            let producers = state.dataset_producers.entry(dataset_id).or_default();
+            match producers.iter_mut().find(|x| x.id == user) {
+                Some(producer) if producer.is_enabled => false,
+                Some(producer) => {
+                    producer.is_enabled = true;
+                    true
+                },
+                None => {
+                    producers.push(ProducerState { id: user, is_enabled: true, created_at: now });
+                    true
+                },
+            }
+        },
+        (DatasetRole::Producer, UpdateMode::Remove) => {
+            let producers = state.dataset_producers.entry(dataset_id).or_default();
+            let count = producers.len();
+            producers.retain(|x| x.id != user);
+            producers.len() != count
+        },
+        (role, UpdateMode::Add) => {
+            let granted = state.dataset_roles.entry(dataset_id).or_default();
+            let changed = !granted.contains(&(user, role));
+            if changed { granted.push((user, role)); }
+            changed
+        },
+        (role, UpdateMode::Remove) => {
+            let granted = state.dataset_roles.entry(dataset_id).or_default();
+            let count = granted.len();
+            granted.retain(|x| *x != (user, role));
+            granted.len() != count
+        },
+    };
+    if changed {
+        log(state, dataset_id, user, role, mode, changed_by, now);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admins_cannot_grant_analyst() {
+        let admin = [DatasetRole::Admin];
+        assert!(!can_assign(&admin, DatasetRole::Analyst, &UpdateMode::Add, false));
+        assert!(can_assign(&admin, DatasetRole::Auditor, &UpdateMode::Add, false));
+        assert!(can_assign(&[DatasetRole::Owner], DatasetRole::Analyst, &UpdateMode::Add, false));
+    }
+
+    #[test]
+    fn roles_cannot_be_self_granted() {
+        let actor = [DatasetRole::Owner, DatasetRole::Admin];
+        assert!(!can_assign(&actor, DatasetRole::Analyst, &UpdateMode::Add, true));
+        assert!(!can_assign(&actor, DatasetRole::Producer, &UpdateMode::Add, true));
+        assert!(can_assign(&actor, DatasetRole::Owner, &UpdateMode::Remove, true));
+    }
+}
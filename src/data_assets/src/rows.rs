@@ -0,0 +1,110 @@
+use crate::types::*;
+use ic_cdk::export::Principal;
+
+pub fn validate(config: &DatasetConfiguration, policies: &[RowPolicy]) -> Result<(), String> {
+    for predicate in policies.iter().flat_map(|x| x.predicates.iter()) {
+        let dimension = config.dimensions
+            .iter()
+            .find(|dim| dim.dimension_id == predicate.dimension_id)
+            .ok_or(format!("Unknown dimension {}", predicate.dimension_id))?;
+        match predicate.operator {
+            RowOperator::Between(_, _) if !dimension.dimension_type.is_metric() => {
+                return Err(format!("Dimension {} is not numerical", dimension.title));
+            },
+            RowOperator::Between(low, high) if low > high => return Err("Empty range".to_string()),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+// Every rule naming the consumer or one of their tiers applies, all of them together.
+pub fn predicates_for(policies: &[RowPolicy], consumer: Principal, tiers: &[String]) -> Vec<RowPredicate> {
+    policies
+        .iter()
+        .filter(|x| match &x.grantee {
+            RowGrantee::User(user) => *user == consumer,
+            RowGrantee::Tier(tier) => tiers.contains(tier),
+        })
+        .flat_map(|x| x.predicates.iter().cloned())
+        .collect()
+}
+
+// A record without a value for a restricted dimension is outside the scope
+pub fn matches(entry: &DatasetEntry, predicates: &[RowPredicate]) -> bool {
+    predicates.iter().all(|predicate| {
+        entry.values
+            .iter()
+            .find(|val| val.dimension_id == predicate.dimension_id)
+            .map(|val| match (&predicate.operator, &val.value) {
+                (RowOperator::Equals(x), value) => x == value,
+                (RowOperator::NotEquals(x), value) => x != value,
+                (RowOperator::OneOf(x), value) => x.contains(value),
+                (RowOperator::Between(low, high), Value::Metric(value)) => low <= value && value <= high,
+                (RowOperator::Between(_, _), _) => false,
+            })
+            .unwrap_or(false)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNTRY: u8 = 1;
+    const REVENUE: u8 = 2;
+
+    fn sale(country: Option<&str>, revenue: u32) -> DatasetEntry {
+        let mut values = vec![DatasetValue { dimension_id: REVENUE, value: Value::Metric(revenue) }];
+        if let Some(country) = country {
+            values.push(DatasetValue { dimension_id: COUNTRY, value: Value::Attribute(country.to_string()) });
+        }
+        DatasetEntry { id: RecordKey::Id(revenue), producer: Principal::anonymous(), values, created_at: 0, updated_at: 0 }
+    }
+
+    fn country(operator: RowOperator) -> RowPredicate {
+        RowPredicate { dimension_id: COUNTRY, operator }
+    }
+
+    fn de() -> Value {
+        Value::Attribute("DE".to_string())
+    }
+
+    #[test]
+    fn operators_match_their_values() {
+        let german = sale(Some("DE"), 100);
+        assert!(matches(&german, &[country(RowOperator::Equals(de()))]));
+        assert!(!matches(&german, &[country(RowOperator::NotEquals(de()))]));
+        assert!(matches(&german, &[country(RowOperator::OneOf(vec![Value::Attribute("FR".to_string()), de()]))]));
+        assert!(matches(&german, &[RowPredicate { dimension_id: REVENUE, operator: RowOperator::Between(100, 200) }]));
+        assert!(!matches(&german, &[RowPredicate { dimension_id: REVENUE, operator: RowOperator::Between(101, 200) }]));
+        // A range never matches a category
+        assert!(!matches(&german, &[country(RowOperator::Between(0, u32::MAX))]));
+    }
+
+    #[test]
+    fn predicates_must_all_hold() {
+        let predicates = [country(RowOperator::Equals(de())), RowPredicate { dimension_id: REVENUE, operator: RowOperator::Between(0, 50) }];
+        assert!(matches(&sale(Some("DE"), 50), &predicates));
+        assert!(!matches(&sale(Some("DE"), 51), &predicates));
+        assert!(matches(&sale(None, 1), &[]));
+    }
+
+    // Even a negative predicate does not reach records missing the column
+    #[test]
+    fn missing_values_are_out_of_scope() {
+        assert!(!matches(&sale(None, 100), &[country(RowOperator::NotEquals(de()))]));
+    }
+
+    #[test]
+    fn policies_apply_by_user_and_tier() {
+        let analyst = Principal::from_slice(&[3; 29]);
+        let policies = vec![
+            RowPolicy { grantee: RowGrantee::User(analyst), predicates: vec![country(RowOperator::Equals(de()))] },
+            RowPolicy { grantee: RowGrantee::Tier("partner".to_string()), predicates: vec![country(RowOperator::NotEquals(de()))] },
+        ];
+        assert_eq!(predicates_for(&policies, analyst, &[]), policies[0].predicates);
+        assert_eq!(predicates_for(&policies, analyst, &["partner".to_string()]).len(), 2);
+        assert!(predicates_for(&policies, Principal::anonymous(), &["public".to_string()]).is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::random::Rng;
+use crate::types::*;
+use itertools::Itertools;
+
+pub const MAX_SAMPLE_SIZE: u32 = 10_000;
+
+// Partial Fisher-Yates over the indices, kept in dataset order afterwards.
+fn random_indices(len: usize, size: usize, rng: &mut Rng) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..len).collect();
+    let size = std::cmp::min(size, len);
+    for i in 0..size {
+        let j = i + rng.below((len - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(size);
+    indices.sort_unstable();
+    indices
+}
+
+// Algorithm R: a single pass over the entries, usable on any iterator.
+pub fn reservoir<'a, I>(entries: I, size: usize, rng: &mut Rng) -> Vec<&'a DatasetEntry>
+where
+    I: Iterator<Item = &'a DatasetEntry>,
+{
+    let mut reservoir: Vec<&DatasetEntry> = Vec::with_capacity(size);
+    for (seen, entry) in entries.enumerate() {
+        if reservoir.len() < size {
+            reservoir.push(entry);
+        } else {
+            let j = rng.below(seen as u64 + 1) as usize;
+            if j < size { reservoir[j] = entry; }
+        }
+    }
+    reservoir
+}
+
+fn stratum_key(entry: &DatasetEntry, dimension_id: u8) -> String {
+    entry.values
+        .iter()
+        .find(|val| val.dimension_id == dimension_id)
+        .map(|val| val.value.to_string())
+        .unwrap_or_default()
+}
+
+// Proportional allocation across strata (largest remainder), random within each stratum.
+fn stratified<'a>(entries: &'a [DatasetEntry], dimension_id: u8, size: usize, rng: &mut Rng) -> Vec<&'a DatasetEntry> {
+    let total = entries.len();
+    if total == 0 || size == 0 { return vec![]; }
+    let strata: Vec<(String, Vec<&DatasetEntry>)> = entries
+        .iter()
+        .map(|entry| (stratum_key(entry, dimension_id), entry))
+        .into_group_map()
+        .into_iter()
+        .sorted_by(|x, y| x.0.cmp(&y.0))
+        .collect();
+    let size = std::cmp::min(size, total);
+    let mut allocation: Vec<(usize, usize, usize)> = strata
+        .iter()
+        .enumerate()
+        .map(|(index, (_, members))| {
+            let exact = members.len() * size;
+            (index, exact / total, exact % total)
+        })
+        .collect();
+    let allocated: usize = allocation.iter().map(|x| x.1).sum();
+    allocation.sort_by(|x, y| y.2.cmp(&x.2).then(x.0.cmp(&y.0)));
+    for slot in allocation.iter_mut().take(size - allocated) {
+        slot.1 += 1;
+    }
+    allocation.sort_by_key(|x| x.0);
+    allocation
+        .iter()
+        .flat_map(|(index, quota, _)| {
+            let members = &strata[*index].1;
+            random_indices(members.len(), *quota, rng)
+                .into_iter()
+                .map(|i| members[i])
+                .collect::<Vec<&DatasetEntry>>()
+        })
+        .collect()
+}
+
+pub fn sample(entries: &[DatasetEntry], method: &SampleMethod, size: u32, seed: u64) -> Vec<DatasetEntry> {
+    let size = std::cmp::min(size, MAX_SAMPLE_SIZE) as usize;
+    let mut rng = Rng::new(seed);
+    match method {
+        SampleMethod::Head => entries.iter().take(size).cloned().collect(),
+        SampleMethod::Random => random_indices(entries.len(), size, &mut rng)
+            .into_iter()
+            .map(|i| entries[i].clone())
+            .collect(),
+        SampleMethod::Reservoir => reservoir(entries.iter(), size, &mut rng)
+            .into_iter()
+            .cloned()
+            .collect(),
+        SampleMethod::Stratified(dimension_id) => stratified(entries, *dimension_id, size, &mut rng)
+            .into_iter()
+            .cloned()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::export::Principal;
+
+    fn entries(strata: &[(&str, usize)]) -> Vec<DatasetEntry> {
+        strata
+            .iter()
+            .flat_map(|(name, count)| std::iter::repeat_n(*name, *count))
+            .enumerate()
+            .map(|(id, name)| DatasetEntry {
+                id: RecordKey::Id(id as u32),
+                producer: Principal::anonymous(),
+                values: vec![DatasetValue { dimension_id: 1, value: Value::Attribute(name.to_string()) }],
+                created_at: 0,
+                updated_at: 0,
+            })
+            .collect()
+    }
+
+    fn ids(sample: &[DatasetEntry]) -> Vec<RecordKey> {
+        sample.iter().map(|entry| entry.id).collect()
+    }
+
+    fn stratum_count(sample: &[DatasetEntry], name: &str) -> usize {
+        sample.iter().filter(|entry| entry.values[0].value == Value::Attribute(name.to_string())).count()
+    }
+
+    #[test]
+    fn same_seed_same_sample() {
+        let entries = entries(&[("a", 60), ("b", 40)]);
+        for method in [SampleMethod::Random, SampleMethod::Reservoir, SampleMethod::Stratified(1)] {
+            let first = sample(&entries, &method, 10, 42);
+            assert_eq!(ids(&first), ids(&sample(&entries, &method, 10, 42)), "{:?}", method);
+            assert_ne!(ids(&first), ids(&sample(&entries, &method, 10, 43)), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn random_samples_are_distinct_and_in_dataset_order() {
+        let entries = entries(&[("a", 100)]);
+        let picked = ids(&sample(&entries, &SampleMethod::Random, 20, 7));
+        let mut sorted = picked.clone();
+        sorted.sort_by_key(|key| match key { RecordKey::Id(id) => *id, RecordKey::User(_) => u32::MAX });
+        sorted.dedup();
+        assert_eq!(picked, sorted);
+        assert_eq!(picked.len(), 20);
+    }
+
+    #[test]
+    fn reservoir_size_is_respected() {
+        let entries = entries(&[("a", 50)]);
+        let mut rng = Rng::new(3);
+        assert_eq!(reservoir(entries.iter(), 10, &mut rng).len(), 10);
+        assert_eq!(reservoir(entries.iter(), 80, &mut rng).len(), 50);
+        assert!(reservoir(entries.iter(), 0, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn sample_size_is_capped() {
+        let entries = entries(&[("a", MAX_SAMPLE_SIZE as usize + 5)]);
+        assert_eq!(sample(&entries, &SampleMethod::Head, u32::MAX, 0).len(), MAX_SAMPLE_SIZE as usize);
+        assert_eq!(sample(&entries, &SampleMethod::Reservoir, u32::MAX, 0).len(), MAX_SAMPLE_SIZE as usize);
+    }
+
+    #[test]
+    fn stratified_allocation_is_proportional() {
+        let entries = entries(&[("a", 50), ("b", 30), ("c", 15), ("d", 5)]);
+        let picked = sample(&entries, &SampleMethod::Stratified(1), 10, 1);
+        // 5, 3, 1.5 and 0.5: the tied remainders go to the first stratum in key order
+        assert_eq!(picked.len(), 10);
+        assert_eq!(stratum_count(&picked, "a"), 5);
+        assert_eq!(stratum_count(&picked, "b"), 3);
+        assert_eq!(stratum_count(&picked, "c"), 2);
+        assert_eq!(stratum_count(&picked, "d"), 0);
+    }
+
+    #[test]
+    fn strata_that_round_to_zero_leave_the_size_intact() {
+        let entries = entries(&[("a", 97), ("b", 2), ("c", 1)]);
+        let picked = sample(&entries, &SampleMethod::Stratified(1), 10, 1);
+        assert_eq!(picked.len(), 10);
+        assert_eq!(stratum_count(&picked, "a"), 10);
+        // Asking for everything takes every stratum whole
+        let everything = sample(&entries, &SampleMethod::Stratified(1), 500, 1);
+        assert_eq!(ids(&everything).len(), 100);
+        assert_eq!(stratum_count(&everything, "c"), 1);
+    }
+}
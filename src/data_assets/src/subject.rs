@@ -0,0 +1,184 @@
+use crate::erasure;
+use crate::tokens;
+use crate::types::*;
+use ic_cdk::export::Principal;
+use itertools::Itertools;
+
+pub const PAGE_SIZE: usize = 500;
+
+// Entries (keyed by or produced by the subject) then queries, in a stable order, split into pages.
+pub fn export(state: &StableState, subject: Principal, page: u32, now: u64) -> DataSubjectExport {
+    let entries: Vec<(u32, &DatasetEntry)> = state.dataset_values
+        .iter()
+        .sorted_by_key(|(id, _)| **id)
+        .flat_map(|(id, values)| {
+            values
+                .iter()
+                .filter(|x| x.id == RecordKey::User(subject) || x.producer == subject)
+                .map(move |x| (*id, x))
+        })
+        .collect();
+    let queries: Vec<(u32, &Query)> = state.queries
+        .iter()
+        .filter(|(_, x)| x.user == subject)
+        .sorted_by_key(|(id, _)| **id)
+        .map(|(id, x)| (*id, x))
+        .collect();
+
+    let start = page as usize * PAGE_SIZE;
+    let end = start + PAGE_SIZE;
+    let total = entries.len() + queries.len();
+    let page_entries = entries
+        .iter()
+        .skip(start)
+        .take(PAGE_SIZE)
+        .map(|(id, x)| (*id, (*x).clone()))
+        .collect();
+    let page_queries = queries
+        .iter()
+        .skip(start.saturating_sub(entries.len()))
+        .take(end.saturating_sub(std::cmp::max(start, entries.len())))
+        .map(|(id, x)| (*id, (*x).clone()))
+        .collect();
+
+    let digest = erasure::subject_digest(&state.pseudonym_salt, &subject);
+    DataSubjectExport {
+        subject,
+        generated_at: now,
+        page,
+        next_page: if end < total { Some(page + 1) } else { None },
+        total_entries: entries.len() as u32,
+        total_queries: queries.len() as u32,
+        roles: DataSubjectRoles {
+            owned_datasets: state.dataset_owners.get(&subject).cloned().unwrap_or_default(),
+            producer_of: state.dataset_producers
+                .iter()
+                .sorted_by_key(|(id, _)| **id)
+                .filter_map(|(id, producers)| {
+                    producers.iter().find(|x| x.id == subject).map(|x| ProducerRole {
+                        dataset_id: *id,
+                        is_enabled: x.is_enabled,
+                        created_at: x.created_at,
+                    })
+                })
+                .collect(),
+            admin: state.admins.contains(&subject),
+            dataset_roles: state.dataset_roles
+                .iter()
+                .flat_map(|(id, granted)| granted.iter().filter(|(user, _)| *user == subject).map(move |(_, role)| (*id, *role)))
+                .sorted()
+                .collect(),
+        },
+        analytics_tokens: state.analytics_tokens
+            .get(&subject)
+            .map(|x| x.iter().map(tokens::info).collect())
+            .unwrap_or_default(),
+        query_templates: state.query_templates
+            .values()
+            .flatten()
+            .filter(|x| x.owner == subject)
+            .cloned()
+            .collect(),
+        privacy_budgets: state.privacy_budgets
+            .iter()
+            .filter_map(|(id, budgets)| budgets.get(&subject).map(|spent| (*id, *spent)))
+            .sorted_by_key(|x| x.0)
+            .collect(),
+        audit_alerts: state.audit_alerts
+            .iter()
+            .flat_map(|(id, alerts)| alerts.iter().filter(|x| x.user == subject).map(move |x| (*id, x.clone())))
+            .sorted_by_key(|x| x.1.query_id)
+            .collect(),
+        erasure_receipts: state.erasure_log
+            .iter()
+            .filter(|x| x.subject_digest == digest)
+            .cloned()
+            .collect(),
+        consents: state.consents
+            .iter()
+            .filter_map(|(id, consents)| consents.get(&subject).map(|purposes| (*id, purposes.clone())))
+            .sorted_by_key(|x| x.0)
+            .collect(),
+        consent_events: state.consent_log
+            .values()
+            .flatten()
+            .filter(|x| x.subject == subject)
+            .sorted_by_key(|x| x.timestamp)
+            .cloned()
+            .collect(),
+        nft_activations: state.nft_activations
+            .iter()
+            .flat_map(|(id, activations)| activations.iter().filter(|x| x.user == subject).map(move |x| (*id, x.clone())))
+            .sorted_by_key(|(id, x)| (*id, x.activated_at))
+            .collect(),
+        direct_grants: state.direct_grants
+            .values()
+            .flatten()
+            .filter(|x| x.user == subject)
+            .sorted_by_key(|x| (x.dataset_id, x.granted_at))
+            .cloned()
+            .collect(),
+        entries: page_entries,
+        queries: page_queries,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject() -> Principal {
+        Principal::from_slice(&[4; 29])
+    }
+
+    fn entry(id: u32, producer: Principal) -> DatasetEntry {
+        DatasetEntry { id: RecordKey::Id(id), producer, values: vec![], created_at: 0, updated_at: 0 }
+    }
+
+    fn query(user: Principal) -> Query {
+        Query {
+            timestamp: 0,
+            user,
+            query_meta: QueryInput { dataset_id: 1, attributes: vec![], metrics: vec![], filters: vec![] },
+            query_state: QueryState::Accepted,
+            is_gdpr: false,
+            gdpr_limit: 0,
+        }
+    }
+
+    // 600 keyed or produced entries over two datasets and 400 queries: pages of 500, entries first
+    fn state() -> StableState {
+        let other = Principal::from_slice(&[5; 29]);
+        let mut state = StableState::default();
+        state.dataset_values.insert(2, (0..300).map(|id| entry(id, subject())).chain((0..50).map(|id| entry(id, other))).collect());
+        let mut keyed: Vec<DatasetEntry> = (0..400).map(|id| entry(id, other)).collect();
+        keyed[0].id = RecordKey::User(subject());
+        for x in keyed.iter_mut().skip(1).take(299) { x.producer = subject(); }
+        state.dataset_values.insert(1, keyed);
+        for id in 0..450 {
+            state.queries.insert(id, query(if id < 400 { subject() } else { other }));
+        }
+        state
+    }
+
+    #[test]
+    fn pages_walk_entries_then_queries() {
+        let state = state();
+        let pages: Vec<DataSubjectExport> = (0..4).map(|page| export(&state, subject(), page, 0)).collect();
+        let sizes: Vec<(usize, usize, Option<u32>)> = pages.iter().map(|x| (x.entries.len(), x.queries.len(), x.next_page)).collect();
+        assert_eq!(sizes, vec![(500, 0, Some(1)), (100, 400, None), (0, 0, None), (0, 0, None)]);
+        assert!(pages.iter().all(|x| x.total_entries == 600 && x.total_queries == 400));
+        // Datasets in id order, and queries in id order with none repeated across pages
+        assert_eq!(pages[0].entries.iter().filter(|x| x.0 == 1).count(), 300);
+        let query_ids: Vec<u32> = pages.iter().flat_map(|x| x.queries.iter().map(|q| q.0)).collect();
+        assert_eq!(query_ids, (0..400).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn a_full_last_page_has_no_next() {
+        let mut state = StableState::default();
+        state.dataset_values.insert(1, (0..PAGE_SIZE as u32).map(|id| entry(id, subject())).collect());
+        let first = export(&state, subject(), 0, 0);
+        assert_eq!((first.entries.len(), first.next_page), (PAGE_SIZE, None));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::types::*;
+use ic_cdk::export::Principal;
+
+pub fn is_visible(template: &QueryTemplate, user: Principal) -> bool {
+    template.is_official || template.owner == user || template.shared_with.contains(&user)
+}
+
+fn check_parameter(parameter: &TemplateParameter, dimension: Option<&DatasetDimension>, value: &Value) -> Result<(), String> {
+    match (&parameter.parameter_type, value) {
+        (ParameterType::Numerical, Value::Metric(_)) => Ok(()),
+        (ParameterType::Text, Value::Attribute(_)) => Ok(()),
+        (ParameterType::Categorical, Value::Attribute(att)) => match dimension.map(|dim| &dim.dimension_type) {
+            Some(DimensionType::Categorical(categories)) if !categories.is_empty() && !categories.contains(att) => {
+                Err(format!("Value {} is not a category of parameter {}", att, parameter.name))
+            },
+            _ => Ok(()),
+        },
+        _ => Err(format!("Parameter {} expects a {:?} value", parameter.name, parameter.parameter_type)),
+    }
+}
+
+pub fn validate(config: &DatasetConfiguration, input: &QueryTemplateInput) -> Result<(), String> {
+    if input.name.trim().is_empty() {
+        return Err("Template name cannot be empty".to_string());
+    }
+    let mut dimensions = input.attributes.clone();
+    dimensions.extend(input.metrics.iter());
+    dimensions.extend(input.filters.iter().map(|x| x.0));
+    dimensions.extend(input.parameters.iter().map(|x| x.dimension_id));
+    match dimensions.iter().find(|id| !config.dimensions.iter().any(|dim| dim.dimension_id == **id)) {
+        Some(id) => Err(format!("Unknown dimension {}", id)),
+        None => Ok(()),
+    }
+}
+
+// Fixed filters followed by one filter per parameter value, in parameter order.
+pub fn bind(config: &DatasetConfiguration, template: &QueryTemplate, values: &[(String, Value)]) -> Result<QueryInput, String> {
+    if let Some((name, _)) = values.iter().find(|(name, _)| !template.parameters.iter().any(|x| &x.name == name)) {
+        return Err(format!("Unknown parameter {}", name));
+    }
+    let mut filters = template.filters.clone();
+    for parameter in template.parameters.iter() {
+        let value = values
+            .iter()
+            .find(|(name, _)| name == &parameter.name)
+            .map(|(_, value)| value)
+            .ok_or(format!("Missing value for parameter {}", parameter.name))?;
+        let dimension = config.dimensions.iter().find(|dim| dim.dimension_id == parameter.dimension_id);
+        check_parameter(parameter, dimension, value)?;
+        filters.push((parameter.dimension_id, value.clone()));
+    }
+    Ok(QueryInput {
+        dataset_id: template.dataset_id,
+        attributes: template.attributes.clone(),
+        metrics: template.metrics.clone(),
+        filters,
+    })
+}
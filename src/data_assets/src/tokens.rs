@@ -0,0 +1,285 @@
+use crate::types::*;
+use ic_cdk::export::Principal;
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+
+pub const TOKEN_BYTES: usize = 32;
+pub const MAX_LIFETIME: u32 = 90 * 24 * 3600;
+pub const MAX_TOKENS: usize = 20;
+pub const MAX_PRESENTERS: usize = 20;
+
+// Who a request acts for, and the columns its token narrows access to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Consumer {
+    pub principal: Principal,
+    pub columns: Option<Vec<u8>>,
+    pub token_id: Option<u32>,
+}
+
+impl Consumer {
+    pub fn direct(principal: Principal) -> Self {
+        Consumer { principal, columns: None, token_id: None }
+    }
+
+    pub fn narrow(&self, mut access: DatasetAccess) -> DatasetAccess {
+        if let Some(columns) = &self.columns {
+            access.columns.retain(|x| columns.contains(x));
+        }
+        access
+    }
+}
+
+pub fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).join("")
+}
+
+pub fn validate(request: &TokenRequest) -> Result<(), String> {
+    if request.lifetime == 0 || request.lifetime > MAX_LIFETIME {
+        return Err(format!("Token lifetime must be between 1 and {} seconds", MAX_LIFETIME));
+    }
+    if request.scope.operations.is_empty() {
+        return Err("Token scope must allow at least one operation".to_string());
+    }
+    if request.scope.datasets.as_ref().map(|x| x.is_empty()).unwrap_or(false) {
+        return Err("Token scope lists no datasets".to_string());
+    }
+    if request.scope.columns.as_ref().map(|x| x.is_empty()).unwrap_or(false) {
+        return Err("Token scope lists no columns".to_string());
+    }
+    Ok(())
+}
+
+pub fn is_live(token: &AnalyticsToken, now: u64) -> bool {
+    token.revoked_at.is_none() && token.expire_at > now
+}
+
+pub fn info(token: &AnalyticsToken) -> AnalyticsTokenInfo {
+    AnalyticsTokenInfo {
+        id: token.id,
+        lifetime: token.lifetime,
+        created_at: token.created_at,
+        expire_at: token.expire_at,
+        revoked_at: token.revoked_at,
+        scope: token.scope.clone(),
+    }
+}
+
+// Expired and revoked tokens are dropped once a principal reaches the limit
+pub fn issue(state: &mut StableState, user: Principal, request: TokenRequest, secret: &[u8], now: u64) -> Result<IssuedToken, String> {
+    validate(&request)?;
+    if secret.len() < TOKEN_BYTES {
+        return Err("Not enough randomness to generate a token".to_string());
+    }
+    let tokens = state.analytics_tokens.entry(user).or_default();
+    if tokens.len() >= MAX_TOKENS {
+        let (live, dead): (Vec<AnalyticsToken>, Vec<AnalyticsToken>) = tokens.drain(..).partition(|x| is_live(x, now));
+        *tokens = live;
+        for token in dead.iter() {
+            state.token_index.remove(&token.token_hash);
+        }
+    }
+    let held = state.analytics_tokens.get(&user).map(|x| x.len()).unwrap_or(0);
+    if held >= MAX_TOKENS {
+        return Err(format!("A principal can hold at most {} active tokens", MAX_TOKENS));
+    }
+    let token = encode(&secret[..TOKEN_BYTES]);
+    state.next_token_id += 1;
+    let record = AnalyticsToken {
+        id: state.next_token_id,
+        token_hash: hash(&token),
+        lifetime: request.lifetime,
+        created_at: now,
+        expire_at: now.saturating_add((request.lifetime as u64).saturating_mul(1_000_000_000)),
+        revoked_at: None,
+        scope: request.scope,
+        usage: TokenUsage::default(),
+    };
+    let info = info(&record);
+    state.token_index.insert(record.token_hash.clone(), user);
+    state.analytics_tokens.entry(user).or_default().push(record);
+    Ok(IssuedToken { token, info })
+}
+
+pub fn revoke(state: &mut StableState, user: Principal, id: u32, now: u64) -> Result<AnalyticsTokenInfo, String> {
+    let token = state.analytics_tokens
+        .get_mut(&user)
+        .and_then(|x| x.iter_mut().find(|x| x.id == id))
+        .ok_or("Token not found")?;
+    if token.revoked_at.is_none() {
+        token.revoked_at = Some(now);
+    }
+    Ok(info(token))
+}
+
+// Whether an export opened with the token may go on; sessions opened without one always may
+pub fn is_held(state: &StableState, user: Principal, token_id: Option<u32>, now: u64) -> bool {
+    match token_id {
+        Some(id) => state.analytics_tokens
+            .get(&user)
+            .and_then(|x| x.iter().find(|x| x.id == id))
+            .map(|x| is_live(x, now))
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+fn live_record<'a>(state: &'a StableState, token: &str, now: u64) -> Result<(Principal, &'a AnalyticsToken), AnalyticsError> {
+    let token_hash = hash(token);
+    let principal = *state.token_index.get(&token_hash).ok_or(AnalyticsError::Unauthorized)?;
+    let record = state.analytics_tokens
+        .get(&principal)
+        .and_then(|x| x.iter().find(|x| x.token_hash == token_hash))
+        .ok_or(AnalyticsError::Unauthorized)?;
+    if !is_live(record, now) {
+        return Err(AnalyticsError::TokenExpired);
+    }
+    Ok((principal, record))
+}
+
+// Datasets a live token may be used on, for calls such as the catalogue that are not tied to one dataset
+pub fn scope(state: &StableState, token: &str, now: u64) -> Result<Option<Vec<u32>>, AnalyticsError> {
+    live_record(state, token, now).map(|(_, record)| record.scope.datasets.clone())
+}
+
+// Resolves a presented token to its holder, checking lifetime and scope for the operation
+pub fn resolve(state: &StableState, token: &str, dataset_id: u32, operation: TokenOperation, now: u64) -> Result<Consumer, AnalyticsError> {
+    let (principal, record) = live_record(state, token, now)?;
+    if !record.scope.datasets.as_ref().map(|x| x.contains(&dataset_id)).unwrap_or(true) {
+        return Err(AnalyticsError::Other(format!("Token is not scoped to dataset {}", dataset_id)));
+    }
+    if !record.scope.operations.contains(&operation) {
+        return Err(AnalyticsError::Other(format!("Token does not allow {:?}", operation)));
+    }
+    Ok(Consumer { principal, columns: record.scope.columns.clone(), token_id: Some(record.id) })
+}
+
+fn token_mut<'a>(state: &'a mut StableState, consumer: &Consumer) -> Option<&'a mut AnalyticsToken> {
+    let id = consumer.token_id?;
+    state.analytics_tokens
+        .get_mut(&consumer.principal)
+        .and_then(|x| x.iter_mut().find(|x| x.id == id))
+}
+
+// Counts an accepted call; direct calls without a token are not tracked
+pub fn record_call(state: &mut StableState, consumer: &Consumer, presenter: Principal, dataset_id: u32, operation: TokenOperation, now: u64) {
+    if let Some(token) = token_mut(state, consumer) {
+        let usage = &mut token.usage;
+        usage.calls += 1;
+        usage.last_used = Some(now);
+        usage.last_dataset = Some(dataset_id);
+        usage.last_operation = Some(operation);
+        match usage.dataset_calls.iter_mut().find(|(id, _)| *id == dataset_id) {
+            Some((_, calls)) => *calls += 1,
+            None => usage.dataset_calls.push((dataset_id, 1)),
+        }
+        if !usage.presenters.contains(&presenter) && usage.presenters.len() < MAX_PRESENTERS {
+            usage.presenters.push(presenter);
+        }
+    }
+}
+
+pub fn record_rows(state: &mut StableState, consumer: &Consumer, rows: usize) {
+    if let Some(token) = token_mut(state, consumer) {
+        token.usage.rows_returned += rows as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn request(lifetime: u32) -> TokenRequest {
+        TokenRequest {
+            lifetime,
+            scope: TokenScope { datasets: None, operations: vec![TokenOperation::Analytics], columns: None },
+        }
+    }
+
+    #[test]
+    fn lifetime_is_counted_in_seconds() {
+        let mut state = StableState::default();
+        let user = Principal::from_slice(&[3; 29]);
+        let now = 1_700_000_000 * SECOND;
+        let issued = issue(&mut state, user, request(60), &[9; TOKEN_BYTES], now).unwrap();
+        assert_eq!(issued.info.expire_at, now + 60 * SECOND);
+        assert!(resolve(&state, &issued.token, 1, TokenOperation::Analytics, now + SECOND).is_ok());
+        assert_eq!(
+            resolve(&state, &issued.token, 1, TokenOperation::Analytics, now + 60 * SECOND),
+            Err(AnalyticsError::TokenExpired),
+        );
+    }
+
+    #[test]
+    fn tokens_are_found_by_their_hash_only() {
+        let mut state = StableState::default();
+        let user = Principal::from_slice(&[3; 29]);
+        let issued = issue(&mut state, user, request(60), &[9; TOKEN_BYTES], 0).unwrap();
+        let stored = &state.analytics_tokens[&user][0];
+        assert_eq!(stored.token_hash, hash(&issued.token));
+        assert_ne!(stored.token_hash, issued.token.as_bytes());
+        assert_eq!(state.token_index.get(&hash(&issued.token)), Some(&user));
+        let consumer = resolve(&state, &issued.token, 1, TokenOperation::Analytics, 0).unwrap();
+        assert_eq!((consumer.principal, consumer.token_id), (user, Some(issued.info.id)));
+        let altered = format!("{}a", &issued.token[..issued.token.len() - 1]);
+        assert_eq!(resolve(&state, &altered, 1, TokenOperation::Analytics, 0), Err(AnalyticsError::Unauthorized));
+        assert!(resolve(&state, &issued.token, 1, TokenOperation::Download, 0).is_err());
+    }
+
+    #[test]
+    fn usage_counts_calls_rows_and_presenters() {
+        let mut state = StableState::default();
+        let user = Principal::from_slice(&[3; 29]);
+        let issued = issue(&mut state, user, request(60), &[9; TOKEN_BYTES], 0).unwrap();
+        let consumer = resolve(&state, &issued.token, 1, TokenOperation::Analytics, 0).unwrap();
+        for i in 0..(MAX_PRESENTERS + 5) {
+            let dataset_id = if i % 2 == 0 { 1 } else { 2 };
+            record_call(&mut state, &consumer, Principal::from_slice(&[i as u8; 29]), dataset_id, TokenOperation::Analytics, i as u64);
+        }
+        record_rows(&mut state, &consumer, 40);
+        record_rows(&mut state, &consumer, 2);
+        // Direct callers have no token to count against
+        record_call(&mut state, &Consumer::direct(user), user, 1, TokenOperation::Analytics, 0);
+        let usage = &state.analytics_tokens[&user][0].usage;
+        assert_eq!((usage.calls, usage.rows_returned), (MAX_PRESENTERS as u64 + 5, 42));
+        assert_eq!(usage.dataset_calls, vec![(1, 13), (2, 12)]);
+        assert_eq!(usage.presenters.len(), MAX_PRESENTERS);
+        assert_eq!((usage.last_used, usage.last_dataset), (Some(MAX_PRESENTERS as u64 + 4), Some(1)));
+    }
+
+    #[test]
+    fn scope_needs_a_live_token() {
+        let mut state = StableState::default();
+        let user = Principal::from_slice(&[3; 29]);
+        let mut scoped = request(60);
+        scoped.scope.datasets = Some(vec![4]);
+        let issued = issue(&mut state, user, scoped, &[9; TOKEN_BYTES], 0).unwrap();
+        assert_eq!(scope(&state, &issued.token, SECOND), Ok(Some(vec![4])));
+        assert_eq!(scope(&state, &issued.token, 60 * SECOND), Err(AnalyticsError::TokenExpired));
+        assert_eq!(scope(&state, "unknown", SECOND), Err(AnalyticsError::Unauthorized));
+    }
+
+    #[test]
+    fn revocation_stops_held_sessions() {
+        let mut state = StableState::default();
+        let user = Principal::from_slice(&[3; 29]);
+        let issued = issue(&mut state, user, request(60), &[9; TOKEN_BYTES], 0).unwrap();
+        assert!(is_held(&state, user, Some(issued.info.id), SECOND));
+        revoke(&mut state, user, issued.info.id, SECOND).unwrap();
+        assert!(!is_held(&state, user, Some(issued.info.id), 2 * SECOND));
+        assert!(is_held(&state, user, None, 2 * SECOND));
+    }
+
+    #[test]
+    fn expiry_saturates() {
+        let mut state = StableState::default();
+        let user = Principal::from_slice(&[3; 29]);
+        let issued = issue(&mut state, user, request(MAX_LIFETIME), &[9; TOKEN_BYTES], u64::MAX - SECOND).unwrap();
+        assert_eq!(issued.info.expire_at, u64::MAX);
+    }
+}
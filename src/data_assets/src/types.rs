@@ -12,6 +12,7 @@ pub struct DatasetConfiguration {
     pub dimensions: Vec<DatasetDimension>,
     pub is_active: bool,
     pub category: Vec<String>,
+    pub official_templates: Vec<String>,
     pub created_at: u64,
     pub updated_at: u64,
 }
@@ -32,6 +33,13 @@ pub enum DimensionType {
     JsonObject,
     File,
     Numerical,
+    Timestamp,
+}
+impl DimensionType {
+    // Dimensions stored as `Value::Metric`; timestamps are seconds since the epoch
+    pub fn is_metric(&self) -> bool {
+        matches!(self, DimensionType::Numerical | DimensionType::Timestamp)
+    }
 }
 
 #[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -99,6 +107,10 @@ pub struct ProducerState {
 #[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct State {
     pub stable: StableState,
+    pub export_sessions: HashMap<String, ExportSession>,
+    pub query_cache: QueryCache,
+    pub access_cache: AccessCache,
+    pub next_purge_at: u64,
 }
 #[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct StableState {
@@ -107,7 +119,37 @@ pub struct StableState {
     pub dataset_owners: HashMap<Principal, Vec<u32>>,
     pub dataset_producers: HashMap<u32, Vec<ProducerState>>,
     pub queries: HashMap<u32, Query>,
-    pub analytics_tokens: HashMap<Principal, AnalyticsToken>,
+    pub analytics_tokens: HashMap<Principal, Vec<AnalyticsToken>>,
+    pub token_index: HashMap<Vec<u8>, Principal>,
+    pub next_token_id: u32,
+    pub query_templates: HashMap<u32, Vec<QueryTemplate>>,
+    pub dataset_versions: HashMap<u32, u64>,
+    pub cache_limits: CacheLimits,
+    pub admins: Vec<Principal>,
+    pub materialized_views: HashMap<u32, Vec<MaterializedView>>,
+    pub privacy_policies: HashMap<u32, PrivacyPolicy>,
+    pub privacy_budgets: HashMap<u32, HashMap<Principal, f64>>,
+    pub audit_alerts: HashMap<u32, Vec<AuditAlert>>,
+    pub erasure_log: Vec<ErasureRecord>,
+    pub consents: HashMap<u32, HashMap<Principal, Vec<ConsentPurpose>>>,
+    pub tier_purposes: HashMap<u32, Vec<(String, Vec<ConsentPurpose>)>>,
+    pub consent_log: HashMap<u32, Vec<ConsentEvent>>,
+    pub pseudonym_salt: Vec<u8>,
+    pub masking_policies: HashMap<u32, MaskingPolicy>,
+    pub row_policies: HashMap<u32, Vec<RowPolicy>>,
+    pub retention_policies: HashMap<u32, Vec<RetentionRule>>,
+    pub purge_log: HashMap<u32, Vec<PurgeRecord>>,
+    pub dataset_deletions: HashMap<u32, DatasetDeletion>,
+    pub dataset_roles: HashMap<u32, Vec<(Principal, DatasetRole)>>,
+    pub role_history: HashMap<u32, Vec<RoleChange>>,
+    pub owner_quorums: HashMap<u32, u32>,
+    pub owner_proposals: HashMap<u32, Vec<OwnerProposal>>,
+    pub ownership_offers: HashMap<u32, Vec<OwnershipOffer>>,
+    pub access_providers: HashMap<u32, AccessProviderConfig>,
+    pub access_cache_ttls: HashMap<u32, u32>,
+    pub access_merge_rules: HashMap<u32, AccessMergeRule>,
+    pub nft_activations: HashMap<u32, Vec<NftActivation>>,
+    pub direct_grants: HashMap<u32, Vec<DirectGrant>>,
     pub next_dataset_id: u32,
     pub next_query_id: u32,
 }
@@ -122,6 +164,8 @@ pub enum UpdateMode {
 pub struct AnalyticsType {
     pub group_key : String,
     pub attributes : Vec<Value>,
+    // Dimension of each attribute value, which follow record order rather than query order
+    pub attribute_ids : Vec<u8>,
     pub metrics : HashMap::<u8, u32>,
     pub count : u32,
 }
@@ -136,7 +180,9 @@ pub struct AnalyticsSuperType {
 pub struct AnayticsPrep {
     pub att_hash : String,
     pub att : Vec<Value>,
+    pub att_ids : Vec<u8>,
     pub met: Vec<DatasetValue>,
+    pub sensitive: Option<Value>,
 }
 
 
@@ -177,9 +223,125 @@ pub enum AnalyticsError {
     Other(String),
 }
 
+// ICRC-7 account
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    pub owner : Principal,
+    pub subaccount : Option<Vec<u8>>,
+}
+
+// Holders of the EXT fractional NFTs linked to the dataset
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExtNftProvider {
+    pub canister_id : Principal,
+}
+
+// Any holder of the collection gets the listed columns
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Icrc7Provider {
+    pub canister_id : Principal,
+    pub tier : Option<String>,
+    pub columns : Vec<u8>,
+    pub is_gdpr : bool,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AllowlistEntry {
+    pub user : Principal,
+    pub tier : Option<String>,
+    pub columns : Vec<u8>,
+    pub is_gdpr : bool,
+}
+
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AllowlistProvider {
+    pub entries : Vec<AllowlistEntry>,
+}
+
+// Answers like the NFT canister would, from in-canister holdings, or fails on demand
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MockProvider {
+    pub holdings : Vec<(Principal, Vec<NftMetadata>)>,
+    pub failure : Option<String>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AccessProviderConfig {
+    ExtNft(ExtNftProvider),
+    Icrc7(Icrc7Provider),
+    Allowlist(AllowlistProvider),
+    Mock(MockProvider),
+}
+
+// How several held NFTs combine: union of columns with GDPR only if all require it, or the reverse
+#[derive(CandidType, Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum AccessMergeRule {
+    #[default]
+    MostPermissive,
+    LeastPermissive,
+}
+
+// When a holder was first seen with a time-limited NFT
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NftActivation {
+    pub user : Principal,
+    pub nft : String,
+    pub activated_at : u64,
+    pub expire_at : u64,
+}
+
+// Access given by the owner without an NFT; the tier selects the privacy profile
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DirectGrantRequest {
+    pub user : Principal,
+    pub columns : Vec<u8>,
+    pub is_gdpr : bool,
+    pub tier : Option<String>,
+    pub expire_at : Option<u64>,
+    pub row_quota : Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DirectGrant {
+    pub dataset_id : u32,
+    pub user : Principal,
+    pub columns : Vec<u8>,
+    pub is_gdpr : bool,
+    pub tier : Option<String>,
+    pub expire_at : Option<u64>,
+    pub row_quota : Option<u64>,
+    pub rows_used : u64,
+    pub granted_by : Principal,
+    pub granted_at : u64,
+    pub updated_at : u64,
+    pub revoked_at : Option<u64>,
+    // Set when a new grant replaced this one; kept as history
+    pub superseded_at : Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessCacheEntry {
+    pub access : DatasetAccess,
+    pub expire_at : u64,
+}
+
+// Provider answers per (dataset, user), volatile
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccessCache {
+    pub entries : HashMap<(u32, Principal), AccessCacheEntry>,
+}
+
+// Pushed by the NFT or sales canister; None matches every dataset or user
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessInvalidation {
+    pub dataset_id : Option<u32>,
+    pub user : Option<Principal>,
+}
+
 #[allow(non_snake_case)]
 #[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct NftMetadata {
+    pub name : Option<String>,
     pub dataAssetId : u32,
     pub isEnabled : bool,
     pub price : u32,
@@ -187,13 +349,641 @@ pub struct NftMetadata {
     pub timeLimitSeconds : u32,
     pub dimensionRestrictList : Vec<u8>,
     pub isGdrpEnabled : bool,
+    // Index of the held token, so a repurchase of the same tier starts a new time limit
+    pub tokenIndex : Option<u32>,
 }
 
-#[allow(non_snake_case)]
+impl fmt::Display for AnalyticsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnalyticsError::Unauthorized => write!(f, "Token not found in backend"),
+            AnalyticsError::TokenExpired => write!(f, "Token has expired or was revoked"),
+            AnalyticsError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<AnalyticsError> for String {
+    fn from(error: AnalyticsError) -> Self {
+        error.to_string()
+    }
+}
+
+// Why an analytics query was refused, so the HTTP gateway can answer with a fitting status
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryError {
+    Unavailable(String),
+    BudgetExhausted(String),
+    Invalid(String),
+    Denied(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Unavailable(msg) | QueryError::BudgetExhausted(msg) | QueryError::Invalid(msg) | QueryError::Denied(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<QueryError> for String {
+    fn from(error: QueryError) -> Self {
+        error.to_string()
+    }
+}
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenOperation {
+    Analytics,
+    Download,
+}
+
+// None means unrestricted
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenScope {
+    pub datasets : Option<Vec<u32>>,
+    pub operations : Vec<TokenOperation>,
+    pub columns : Option<Vec<u8>>,
+}
+
+// Only the sha256 of the token is kept
 #[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct AnalyticsToken {
+    pub id : u32,
+    pub token_hash : Vec<u8>,
+    pub lifetime : u32,
+    pub created_at : u64,
+    pub expire_at : u64,
+    pub revoked_at : Option<u64>,
+    pub scope : TokenScope,
+    pub usage : TokenUsage,
+}
+
+// Presenters are the distinct callers that sent the token, anonymous for HTTP
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub calls : u64,
+    pub rows_returned : u64,
+    pub last_used : Option<u64>,
+    pub last_dataset : Option<u32>,
+    pub last_operation : Option<TokenOperation>,
+    pub dataset_calls : Vec<(u32, u64)>,
+    pub presenters : Vec<Principal>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenRequest {
+    pub lifetime : u32,
+    pub scope : TokenScope,
+}
+
+// The plaintext token is only ever returned here
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IssuedToken {
     pub token : String,
+    pub info : AnalyticsTokenInfo,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ExportFormat {
+    Csv,
+    JsonLines,
+    Columnar,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportChunk {
+    pub format : ExportFormat,
+    pub content_type : String,
+    pub chunk : u32,
+    pub chunk_count : u32,
+    pub row_count : u32,
+    pub data : Vec<u8>,
+    // Export session serving the remaining chunks, when there are any
+    pub session : Option<String>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ExportSource {
+    // Rows as released when the export started, so chunk boundaries hold while entries arrive
+    Dataset { dataset_id : u32, columns : Vec<u8>, entries : Vec<DatasetEntry> },
+    Analytics { query : QueryInput, result : AnalyticsSuperType },
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportSession {
+    pub user : Principal,
+    pub source : ExportSource,
+    pub format : ExportFormat,
+    pub expire_at : u64,
+    pub row_limit : Option<u64>,
+    pub token_id : Option<u32>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequest {
+    pub method : String,
+    pub url : String,
+    pub headers : Vec<(String, String)>,
+    pub body : Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpResponse {
+    pub status_code : u16,
+    pub headers : Vec<(String, String)>,
+    pub body : Vec<u8>,
+    pub streaming_strategy : Option<StreamingStrategy>,
+    pub upgrade : Option<bool>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum StreamingStrategy {
+    Callback {
+        callback : candid::Func,
+        token : StreamingCallbackToken,
+    },
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+// The session id is random; the analytics token never leaves the request that opened it
+pub struct StreamingCallbackToken {
+    pub session : String,
+    pub chunk : u32,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct StreamingCallbackHttpResponse {
+    pub body : Vec<u8>,
+    pub token : Option<StreamingCallbackToken>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SampleMethod {
+    Head,
+    Random,
+    Reservoir,
+    Stratified(u8),
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SampleRequest {
+    pub dataset_id : u32,
+    pub size : u32,
+    pub method : SampleMethod,
+    pub seed : Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SampleResult {
+    pub seed : u64,
+    pub entries : Vec<DatasetEntry>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ParameterType {
+    Categorical,
+    Numerical,
+    Text,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TemplateParameter {
+    pub name : String,
+    pub dimension_id : u8,
+    pub parameter_type : ParameterType,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueryTemplateInput {
+    pub name : String,
+    pub description : String,
+    pub attributes : Vec<u8>,
+    pub metrics : Vec<u8>,
+    pub filters : Vec<(u8, Value)>,
+    pub parameters : Vec<TemplateParameter>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueryTemplate {
+    pub name : String,
+    pub dataset_id : u32,
+    pub owner : Principal,
+    pub description : String,
+    pub attributes : Vec<u8>,
+    pub metrics : Vec<u8>,
+    pub filters : Vec<(u8, Value)>,
+    pub parameters : Vec<TemplateParameter>,
+    pub shared_with : Vec<Principal>,
+    pub is_official : bool,
+    pub created_at : u64,
+    pub updated_at : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub dataset_id : u32,
+    pub result : AnalyticsSuperType,
+    pub size_bytes : u64,
+    pub last_used : u64,
+}
+
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueryCache {
+    pub entries : HashMap<String, CacheEntry>,
+    pub size_bytes : u64,
+    pub tick : u64,
+    pub hits : u64,
+    pub misses : u64,
+    pub evictions : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheLimits {
+    pub max_bytes : u64,
+    pub max_entries : u32,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueryCacheStats {
+    pub entries : u32,
+    pub size_bytes : u64,
+    pub limits : CacheLimits,
+    pub hits : u64,
+    pub misses : u64,
+    pub evictions : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaterializedViewInput {
+    pub name : String,
+    pub attributes : Vec<u8>,
+    pub metrics : Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ViewCell {
+    pub attributes : Vec<DatasetValue>,
+    pub metrics : HashMap<u8, u32>,
+    pub count : u32,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaterializedView {
+    pub name : String,
+    pub dataset_id : u32,
+    pub attributes : Vec<u8>,
+    pub metrics : Vec<u8>,
+    pub cells : HashMap<String, ViewCell>,
+    pub created_at : u64,
+    pub updated_at : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaterializedViewInfo {
+    pub name : String,
+    pub attributes : Vec<u8>,
+    pub metrics : Vec<u8>,
+    pub cell_count : u32,
+    pub entry_count : u32,
+    pub updated_at : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NoiseMechanism {
+    Laplace,
+    Gaussian { delta : f64 },
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MetricBound {
+    pub dimension_id : u8,
+    pub lower : u32,
+    pub upper : u32,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DifferentialPrivacyConfig {
+    pub mechanism : NoiseMechanism,
+    pub epsilon_per_query : f64,
+    pub budget_per_consumer : f64,
+    pub bounds : Vec<MetricBound>,
+    // Chance that a group holding a single record is released; defaults to the Gaussian delta
+    pub threshold_delta : Option<f64>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LDiversity {
+    pub dimension_id : u8,
+    pub l : u32,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrivacyPolicy {
+    pub k_threshold : u32,
+    pub tier_thresholds : Vec<(String, u32)>,
+    pub l_diversity : Option<LDiversity>,
+    pub secondary_suppression : bool,
+    pub query_auditing : AuditMode,
+    pub differential_privacy : Option<DifferentialPrivacyConfig>,
+    pub pseudonymization : PseudonymConfig,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AuditMode {
+    Off,
+    Flag,
+    Refuse,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditAlert {
+    pub query_id : u32,
+    pub user : Principal,
+    pub timestamp : u64,
+    pub reason : String,
+    pub overlapping_query : Option<u32>,
+    pub refused : bool,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrivacyBudgetStatus {
+    pub budget : f64,
+    pub spent : f64,
+    pub remaining : f64,
+    pub epsilon_per_query : f64,
+}
+
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DatasetAccess {
+    pub columns : Vec<u8>,
+    pub is_gdpr : bool,
+    pub tiers : Vec<String>,
+    pub expire_at : Option<u64>,
+    // Set when a direct grant with a row quota is the only source, so served rows are charged to it
+    pub metered : bool,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErasureItem {
+    pub dataset_id : u32,
+    pub removed_entries : u32,
+    pub entries_digest : Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErasureRecord {
+    pub receipt_id : u32,
+    pub subject_digest : Vec<u8>,
+    pub timestamp : u64,
+    pub items : Vec<ErasureItem>,
+    pub previous_hash : Vec<u8>,
+    pub hash : Vec<u8>,
+}
+
+// Public part of an erasure record: hash = sha256(previous_hash || content_digest)
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErasureLink {
+    pub receipt_id : u32,
+    pub previous_hash : Vec<u8>,
+    pub content_digest : Vec<u8>,
+    pub hash : Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErasureReceipt {
+    pub record : ErasureRecord,
+    pub chain_head : Vec<u8>,
+    pub certificate : Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AnalyticsTokenInfo {
+    pub id : u32,
     pub lifetime : u32,
     pub created_at : u64,
     pub expire_at : u64,
+    pub revoked_at : Option<u64>,
+    pub scope : TokenScope,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DataSubjectRoles {
+    pub owned_datasets : Vec<u32>,
+    pub producer_of : Vec<ProducerRole>,
+    pub admin : bool,
+    pub dataset_roles : Vec<(u32, DatasetRole)>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProducerRole {
+    pub dataset_id : u32,
+    pub is_enabled : bool,
+    pub created_at : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DataSubjectExport {
+    pub subject : Principal,
+    pub generated_at : u64,
+    pub page : u32,
+    pub next_page : Option<u32>,
+    pub total_entries : u32,
+    pub total_queries : u32,
+    pub roles : DataSubjectRoles,
+    pub analytics_tokens : Vec<AnalyticsTokenInfo>,
+    pub query_templates : Vec<QueryTemplate>,
+    pub privacy_budgets : Vec<(u32, f64)>,
+    pub audit_alerts : Vec<(u32, AuditAlert)>,
+    pub erasure_receipts : Vec<ErasureRecord>,
+    pub consents : Vec<(u32, Vec<ConsentPurpose>)>,
+    pub consent_events : Vec<ConsentEvent>,
+    pub nft_activations : Vec<(u32, NftActivation)>,
+    pub direct_grants : Vec<DirectGrant>,
+    pub entries : Vec<(u32, DatasetEntry)>,
+    pub queries : Vec<(u32, Query)>,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ConsentPurpose {
+    Analytics,
+    Download,
+    Resale,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConsentEvent {
+    pub subject : Principal,
+    pub dataset_id : u32,
+    pub purpose : ConsentPurpose,
+    pub mode : UpdateMode,
+    pub timestamp : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PseudonymMode {
+    Stable,
+    Rotating(u64),
+    Removed,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PseudonymScope {
+    Dataset,
+    Consumer,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PseudonymConfig {
+    pub mode : PseudonymMode,
+    pub scope : PseudonymScope,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MaskRule {
+    Hash,
+    Truncate(u32),
+    Redact,
+    Bucket(u32),
+    Generalize(Vec<(String, String)>),
+    GeoRound(f64),
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ColumnMask {
+    pub dimension_id : u8,
+    pub rule : MaskRule,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaskingProfile {
+    pub name : String,
+    pub masks : Vec<ColumnMask>,
+}
+
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MaskingPolicy {
+    pub profiles : Vec<MaskingProfile>,
+    pub tier_profiles : Vec<(String, String)>,
+    pub default_profile : Option<String>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RowOperator {
+    Equals(Value),
+    NotEquals(Value),
+    OneOf(Vec<Value>),
+    Between(u32, u32),
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RowPredicate {
+    pub dimension_id : u8,
+    pub operator : RowOperator,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RowGrantee {
+    User(Principal),
+    Tier(String),
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RowPolicy {
+    pub grantee : RowGrantee,
+    pub predicates : Vec<RowPredicate>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessExplanation {
+    pub dataset_id : u32,
+    pub columns : Vec<u8>,
+    pub tiers : Vec<String>,
+    pub is_gdpr : bool,
+    pub k_threshold : Option<u32>,
+    pub consent_purposes : Vec<ConsentPurpose>,
+    pub masks : Vec<ColumnMask>,
+    pub row_predicates : Vec<RowPredicate>,
+    pub pseudonymization : PseudonymConfig,
+    pub access_expires_at : Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RetentionRule {
+    CreatedAt { max_age_days : u32 },
+    Dimension { dimension_id : u8, duration_days : u32 },
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PurgeRecord {
+    pub dataset_id : u32,
+    pub timestamp : u64,
+    pub removed_entries : u32,
+    pub entries_digest : Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PurgePreview {
+    pub dataset_id : u32,
+    pub expired_entries : u32,
+    pub next_purge_entries : u32,
+    pub next_purge_at : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DatasetDeletion {
+    pub dataset_id : u32,
+    pub name : String,
+    pub requested_by : Principal,
+    pub requested_at : u64,
+    pub purge_at : u64,
+    pub purged_at : Option<u64>,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum DatasetRole {
+    Owner,
+    Admin,
+    Producer,
+    Analyst,
+    Auditor,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoleChange {
+    pub dataset_id : u32,
+    pub user : Principal,
+    pub role : DatasetRole,
+    pub mode : UpdateMode,
+    pub changed_by : Principal,
+    pub timestamp : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OwnerAction {
+    DeleteDataset,
+    AddOwner(Principal),
+    RemoveOwner(Principal),
+    SetQuorum(u32),
+    SetRetentionPolicy(Vec<RetentionRule>),
+    ResetPrivacyBudget(Principal),
+    DeleteMaterializedView(String),
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OwnerProposal {
+    pub dataset_id : u32,
+    pub action : OwnerAction,
+    pub approvals : Vec<Principal>,
+    pub created_at : u64,
+    pub expires_at : u64,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OwnershipOffer {
+    pub dataset_id : u32,
+    pub from : Principal,
+    pub to : Principal,
+    pub offered_at : u64,
+    pub expires_at : u64,
 }
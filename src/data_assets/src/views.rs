@@ -0,0 +1,219 @@
+use crate::privacy::{self, DisclosureControl};
+use crate::types::*;
+use itertools::Itertools;
+use std::collections::HashMap;
+
+// Cells keep attribute values in record order, exactly like `fetch_analytics` groups them.
+fn cell_attributes(view: &MaterializedView, entry: &DatasetEntry) -> Vec<DatasetValue> {
+    entry.values
+        .iter()
+        .filter(|val| view.attributes.contains(&val.dimension_id))
+        .cloned()
+        .collect()
+}
+
+fn cell_key(attributes: &[DatasetValue]) -> String {
+    attributes.iter().map(|val| format!("{}={}", val.dimension_id, val.value)).join("--")
+}
+
+pub fn apply(view: &mut MaterializedView, entry: &DatasetEntry, mode: &UpdateMode, now: u64) {
+    let attributes = cell_attributes(view, entry);
+    let key = cell_key(&attributes);
+    let cell = view.cells.entry(key.clone()).or_insert(ViewCell {
+        attributes,
+        metrics: HashMap::new(),
+        count: 0,
+    });
+    for metric in view.metrics.iter() {
+        if let Some(value) = entry.values.iter().find(|val| val.dimension_id == *metric) {
+            let sum = cell.metrics.entry(*metric).or_insert(0);
+            if let Value::Metric(x) = value.value {
+                *sum = match mode {
+                    UpdateMode::Add => sum.saturating_add(x),
+                    UpdateMode::Remove => sum.saturating_sub(x),
+                };
+            }
+        }
+    }
+    match mode {
+        UpdateMode::Add => cell.count += 1,
+        UpdateMode::Remove => cell.count = cell.count.saturating_sub(1),
+    }
+    if cell.count == 0 {
+        view.cells.remove(&key);
+    }
+    view.updated_at = now;
+}
+
+pub fn rebuild(view: &mut MaterializedView, entries: &[DatasetEntry], now: u64) {
+    view.cells.clear();
+    for entry in entries.iter() {
+        apply(view, entry, &UpdateMode::Add, now);
+    }
+}
+
+// l-diversity needs the raw sensitive values, which views do not keep
+pub fn can_answer(view: &MaterializedView, query: &QueryInput, control: &DisclosureControl) -> bool {
+    control.l_diversity.is_none()
+        && query.attributes.iter().all(|id| view.attributes.contains(id))
+        && query.metrics.iter().all(|id| view.metrics.contains(id))
+        && query.filters.iter().all(|(id, _)| view.attributes.contains(id))
+}
+
+// Rolls the view cells up to the query group-by; mirrors the stages and counts of `fetch_analytics`.
+pub fn answer(view: &MaterializedView, query: &QueryInput, control: &DisclosureControl) -> AnalyticsSuperType {
+    let op0_size: u32 = view.cells.values().map(|cell| cell.count).sum();
+    let cells: Vec<&ViewCell> = view.cells
+        .values()
+        .filter(|cell| {
+            !query.filters.iter().any(|(id, value)| cell.attributes.iter().any(|val| val.dimension_id == *id && val.value == *value))
+        })
+        .collect();
+    let op1_size: u32 = cells.iter().map(|cell| cell.count).sum();
+    let mut groups: Vec<(String, Vec<Value>, Vec<u8>, &ViewCell)> = cells
+        .into_iter()
+        .map(|cell| {
+            let (attribute_ids, attributes): (Vec<u8>, Vec<Value>) = cell.attributes
+                .iter()
+                .filter(|val| query.attributes.contains(&val.dimension_id))
+                .map(|val| (val.dimension_id, val.value.clone()))
+                .unzip();
+            (attributes.iter().join("--"), attributes, attribute_ids, cell)
+        })
+        .collect();
+    groups.sort_by(|x, y| x.0.cmp(&y.0));
+    let mut analytics = groups
+        .into_iter()
+        .group_by(|x| x.0.clone())
+        .into_iter()
+        .map(|(group_key, members)| {
+            let mut attributes = vec![];
+            let mut attribute_ids = vec![];
+            let mut metrics = HashMap::<u8, u32>::new();
+            let mut count = 0;
+            for (_, att, ids, cell) in members {
+                if attributes.is_empty() {
+                    attributes = att;
+                    attribute_ids = ids;
+                }
+                count += cell.count;
+                for metric in query.metrics.iter() {
+                    if let Some(sum) = cell.metrics.get(metric) {
+                        let total = metrics.entry(*metric).or_insert(0);
+                        *total = total.saturating_add(*sum);
+                    }
+                }
+            }
+            AnalyticsType { group_key, attributes, attribute_ids, metrics, count }
+        })
+        .collect::<Vec<AnalyticsType>>();
+    let op3_size = analytics.len() as u32;
+    if control.is_enabled() {
+        privacy::suppress(&mut analytics, &HashMap::new(), control);
+    }
+    let op4_size = analytics.len() as u32;
+    let op1_size = if control.is_enabled() { 0 } else { op1_size };
+    AnalyticsSuperType {
+        analytics,
+        counts: (op0_size, op1_size, op1_size, op3_size, op4_size),
+    }
+}
+
+pub fn info(view: &MaterializedView) -> MaterializedViewInfo {
+    MaterializedViewInfo {
+        name: view.name.clone(),
+        attributes: view.attributes.clone(),
+        metrics: view.metrics.clone(),
+        cell_count: view.cells.len() as u32,
+        entry_count: view.cells.values().map(|cell| cell.count).sum(),
+        updated_at: view.updated_at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::EntryPolicy;
+    use crate::{fetch_analytics, STATE};
+    use ic_cdk::export::Principal;
+
+    const REGION: u8 = 1;
+    const JOB: u8 = 2;
+    const SALARY: u8 = 3;
+
+    fn person(id: u32, region: &str, job: &str, salary: u32) -> DatasetEntry {
+        DatasetEntry {
+            id: RecordKey::Id(id),
+            producer: Principal::from_slice(&[id as u8; 29]),
+            values: vec![
+                DatasetValue { dimension_id: REGION, value: Value::Attribute(region.to_string()) },
+                DatasetValue { dimension_id: JOB, value: Value::Attribute(job.to_string()) },
+                DatasetValue { dimension_id: SALARY, value: Value::Metric(salary) },
+            ],
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn view() -> MaterializedView {
+        MaterializedView {
+            name: "by_region_job".to_string(),
+            dataset_id: 1,
+            attributes: vec![REGION, JOB],
+            metrics: vec![SALARY],
+            cells: HashMap::new(),
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn query(attributes: Vec<u8>, filters: Vec<(u8, &str)>) -> QueryInput {
+        QueryInput {
+            dataset_id: 1,
+            attributes,
+            metrics: vec![SALARY],
+            filters: filters.into_iter().map(|(id, x)| (id, Value::Attribute(x.to_string()))).collect(),
+        }
+    }
+
+    // Compares the view with a full scan of the entries currently stored
+    fn assert_matches_scan(view: &MaterializedView, entries: &[DatasetEntry]) {
+        STATE.with(|map| map.borrow_mut().stable.dataset_values.insert(1, entries.to_vec()));
+        let entry_policy = STATE.with(|map| EntryPolicy::new(&map.borrow().stable, 1, Principal::anonymous(), &[], ConsentPurpose::Analytics, 0));
+        let control = DisclosureControl::default();
+        for query in [
+            query(vec![], vec![]),
+            query(vec![REGION], vec![]),
+            query(vec![JOB, REGION], vec![]),
+            query(vec![JOB], vec![(REGION, "S")]),
+        ] {
+            assert!(can_answer(view, &query, &control));
+            let scanned = fetch_analytics(1, query.attributes.clone(), query.metrics.clone(), query.filters.clone(), &control, &[], &entry_policy);
+            assert_eq!(answer(view, &query, &control), scanned, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn incremental_view_matches_a_full_scan() {
+        let mut view = view();
+        let mut entries = vec![];
+        for (id, (region, job, salary)) in [("N", "clerk", 30), ("N", "ceo", 200), ("S", "nurse", 40), ("S", "nurse", 45), ("S", "pilot", 90)].into_iter().enumerate() {
+            // New entries go first, as `put_entry` stores them
+            let entry = person(id as u32, region, job, salary);
+            apply(&mut view, &entry, &UpdateMode::Add, 1);
+            entries.insert(0, entry);
+            assert_matches_scan(&view, &entries);
+        }
+        // Erasure removes the entries from the store and subtracts them from the view
+        for id in [1, 3] {
+            let position = entries.iter().position(|x| x.id == RecordKey::Id(id)).unwrap();
+            let erased = entries.remove(position);
+            apply(&mut view, &erased, &UpdateMode::Remove, 2);
+            assert_matches_scan(&view, &entries);
+        }
+        assert!(!view.cells.values().any(|cell| cell.attributes.iter().any(|val| val.value == Value::Attribute("ceo".to_string()))));
+        let mut rebuilt = self::view();
+        rebuild(&mut rebuilt, &entries, 2);
+        assert_eq!(rebuilt.cells, view.cells);
+    }
+}